@@ -0,0 +1,168 @@
+//! The task that runs applies, so a slow scxctl never holds up the event
+//! loop. It is fed through a single slot: a job handed over while another
+//! one runs replaces whatever was still waiting, so a flurry of profile
+//! changes ends in at most one switch after the current one.
+
+use anyhow::Result;
+use scx_power_sync_dbus::{ApplyOptions, Mode, Profile, ScxSettings, apply_mode};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Notify, mpsc};
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span};
+
+/// `mode` for `profile`, as decided while the config was at `generation`.
+pub struct Job {
+    pub profile: Profile,
+    pub generation: u64,
+    pub mode: Mode,
+    pub scx: ScxSettings,
+    pub opts: ApplyOptions,
+    /// Entered for the apply, so its log lines carry the profile.
+    pub span: Span,
+}
+
+/// Outcome of a `Job`, or of the startup apply.
+pub struct Finished {
+    pub profile: Profile,
+    pub generation: u64,
+    pub mode: Mode,
+    pub result: Result<()>,
+}
+
+#[derive(Default)]
+struct Slot {
+    waiting: Option<Job>,
+    /// Profile and generation of the job the task took last; it may have
+    /// finished already.
+    taken: Option<(Profile, u64)>,
+}
+
+#[derive(Default)]
+struct Shared {
+    slot: Mutex<Slot>,
+    wake: Notify,
+}
+
+/// The event loop's handle on the apply task. Only the loop touches it, so
+/// the count of unfinished jobs lives here rather than in `Shared`.
+pub struct Applier {
+    shared: Arc<Shared>,
+    finished: mpsc::UnboundedReceiver<Finished>,
+    task: JoinHandle<()>,
+    /// Jobs handed over whose `Finished` hasn't been received yet.
+    outstanding: usize,
+    /// Profile and generation of the newest of them.
+    target: Option<(Profile, u64)>,
+}
+
+impl Applier {
+    pub fn spawn() -> Self {
+        let shared = Arc::new(Shared::default());
+        let (done_tx, finished) = mpsc::unbounded_channel();
+        let task = tokio::spawn(run(shared.clone(), done_tx));
+        Applier {
+            shared,
+            finished,
+            task,
+            outstanding: 0,
+            target: None,
+        }
+    }
+
+    /// Queue `job` behind the running one, replacing any job still waiting;
+    /// returns the profile of the one replaced.
+    pub fn submit(&mut self, job: Job) -> Option<Profile> {
+        self.target = Some((job.profile, job.generation));
+        let replaced = self.shared.slot.lock().unwrap().waiting.replace(job);
+        if replaced.is_none() {
+            self.outstanding += 1;
+        }
+        self.shared.wake.notify_one();
+        replaced.map(|job| job.profile)
+    }
+
+    /// Drop the job still waiting, if the task hasn't taken it yet.
+    pub fn cancel_waiting(&mut self) -> Option<Profile> {
+        let mut slot = self.shared.slot.lock().unwrap();
+        let cancelled = slot.waiting.take()?;
+        self.outstanding -= 1;
+        self.target = slot.taken.filter(|_| self.outstanding > 0);
+        Some(cancelled.profile)
+    }
+
+    /// Whether a job is running or waiting, as far as the loop has seen.
+    pub fn is_busy(&self) -> bool {
+        self.outstanding > 0
+    }
+
+    /// What will be in effect once every unfinished job has run, with the
+    /// generation it was decided at; `None` when idle.
+    pub fn target(&self) -> Option<(Profile, u64)> {
+        self.target
+    }
+
+    /// Profile and generation of the job being applied, while the task is
+    /// busy.
+    pub fn running(&self) -> Option<(Profile, u64)> {
+        let slot = self.shared.slot.lock().unwrap();
+        slot.taken.filter(|_| self.is_busy())
+    }
+
+    /// The next job to finish; never resolves while none is unfinished.
+    pub async fn finished(&mut self) -> Finished {
+        if self.outstanding == 0 {
+            return std::future::pending().await;
+        }
+        let Some(done) = self.finished.recv().await else {
+            return std::future::pending().await;
+        };
+        self.outstanding -= 1;
+        if self.outstanding == 0 {
+            self.target = None;
+        }
+        done
+    }
+
+    /// Stop the task, killing any scxctl it runs (`kill_on_drop`).
+    pub fn abort(&mut self) {
+        self.task.abort();
+        self.shared.slot.lock().unwrap().waiting = None;
+        self.outstanding = 0;
+        self.target = None;
+    }
+}
+
+impl Drop for Applier {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn run(shared: Arc<Shared>, done: mpsc::UnboundedSender<Finished>) {
+    loop {
+        shared.wake.notified().await;
+        loop {
+            let job = {
+                let mut slot = shared.slot.lock().unwrap();
+                let Some(job) = slot.waiting.take() else {
+                    break;
+                };
+                slot.taken = Some((job.profile, job.generation));
+                job
+            };
+            let result = apply_mode(&job.mode, &job.scx, &job.opts)
+                .instrument(job.span)
+                .await
+                .map_err(anyhow::Error::from);
+            let finished = Finished {
+                profile: job.profile,
+                generation: job.generation,
+                mode: job.mode,
+                result,
+            };
+            if done.send(finished).is_err() {
+                return;
+            }
+        }
+    }
+}
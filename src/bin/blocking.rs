@@ -0,0 +1,307 @@
+//! `scx-power-sync-dbus` without tokio, for installs where the async daemon
+//! is more than needed: follows power-profiles-daemon's `ActiveProfile`
+//! through zbus's blocking API and applies modes with the shared library.
+//! Only the core loop is here; see "Blocking build" in the README for what
+//! the full daemon adds.
+
+use anyhow::{Context, Result, anyhow};
+use clap::Parser;
+use scx_power_sync_dbus::{
+    ApplyOptions, BusKind, Config, DecodeError, MissingSchedExt, PPD_CANDIDATES, PowerSource,
+    PpdBus, Profile, SourceKind, apply_mode, block_on, check_sched_args, decode_active_profile,
+    detect_scxctl_version, ensure_bin, load_config, sched_ext_available,
+};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use tracing::{debug, info, warn};
+use zbus::blocking::fdo::PropertiesProxy;
+use zbus::blocking::{Connection, Proxy};
+use zbus::fdo::PropertiesChanged;
+use zbus::names::InterfaceName;
+use zvariant::{OwnedValue, Value};
+
+const UPOWER_DEST: &str = "org.freedesktop.UPower";
+const UPOWER_PATH: &str = "/org/freedesktop/UPower";
+const UPOWER_IFACE: &str = "org.freedesktop.UPower";
+
+#[derive(Debug, Parser)]
+#[command(version, about = "scx-power-sync-dbus built without tokio")]
+struct Cli {
+    /// Load this configuration file instead of searching the default locations.
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Log the scxctl commands that would run instead of executing them.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Re-apply modes even when scxctl reports the same scheduler and args.
+    #[arg(long)]
+    force: bool,
+
+    /// Parse and validate the configuration, print a summary, and exit.
+    #[arg(long)]
+    verify_config: bool,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "info".parse().unwrap()),
+        )
+        .init();
+
+    let mut config = load_config(cli.config.as_deref()).context("load configuration")?;
+    if cli.verify_config {
+        for problem in check_sched_args(&config) {
+            warn!("{problem}");
+        }
+        print!("{}", config.summary());
+        return Ok(());
+    }
+    check_supported(&config)?;
+
+    let opts = ApplyOptions {
+        dry_run: cli.dry_run,
+        force: cli.force,
+    };
+    if opts.dry_run {
+        info!("dry-run: scxctl start/switch will be logged, not executed");
+    } else {
+        ensure_bin(&config.scx.bin)?;
+        config.scx.version = block_on(detect_scxctl_version(&config.scx));
+    }
+    run(&config, &opts)
+}
+
+/// Refuse what this build can't do, and say which settings it ignores.
+fn check_supported(config: &Config) -> Result<()> {
+    if config.source == SourceKind::Tuned {
+        return Err(anyhow!(
+            "source: tuned needs the full daemon; the blocking build only follows power-profiles-daemon"
+        ));
+    }
+    if config.missing_sched_ext == MissingSchedExt::Exit && !sched_ext_available() {
+        return Err(anyhow!(
+            "this kernel has no sched_ext support (missing_sched_ext: exit)"
+        ));
+    }
+    let ignored = [
+        ("stop_on_exit", config.stop_on_exit),
+        ("state_file", config.state_file.is_some()),
+        (
+            "min_switch_interval_ms",
+            !config.min_switch_interval.is_zero(),
+        ),
+        (
+            "performance_degraded",
+            !config.performance_degraded.is_empty(),
+        ),
+        ("ignore_holds", config.ignore_holds),
+        (
+            "cooldown_after_failures",
+            config.cooldown_after_failures > 0,
+        ),
+        ("poll_interval_ms", !config.poll_interval.is_zero()),
+        ("reapply_on_resume", config.reapply_on_resume),
+    ];
+    for (key, _) in ignored.iter().filter(|(_, set)| *set) {
+        warn!("[blocking] {key} is not supported by this build and is ignored");
+    }
+    Ok(())
+}
+
+/// Apply the startup profile, then every change after `debounce` of quiet.
+/// Returns only when the signal subscription ends, e.g. because the bus
+/// went away; the service manager is expected to restart us.
+fn run(config: &Config, opts: &ApplyOptions) -> Result<()> {
+    let conn = match config.bus {
+        BusKind::System => Connection::system(),
+        BusKind::Session => Connection::session(),
+    }
+    .with_context(|| format!("connect to the {} D-Bus", config.bus.as_str()))?;
+    let bus = match &config.ppd_dbus {
+        Some(bus) => bus.clone(),
+        None => detect_ppd_bus(&conn),
+    };
+    let props = PropertiesProxy::new(&conn, bus.name.clone(), bus.path.clone())
+        .context("create PPD Properties proxy")?;
+
+    // Subscribed before the startup read, so a change in between isn't lost.
+    let changes = subscribe(&props, &bus)?;
+    if !config.startup_delay.is_zero() {
+        info!(
+            "[startup] waiting {:?} before the startup sync",
+            config.startup_delay
+        );
+        thread::sleep(config.startup_delay);
+    }
+    let mut last = None;
+    match read_active_profile(&props, &bus) {
+        Ok(value) => handle(
+            config,
+            &conn,
+            opts,
+            decode_active_profile(&value, config),
+            &mut last,
+        ),
+        Err(e) => warn!("[startup] {e:#}; waiting for the next change"),
+    }
+
+    loop {
+        let value = changes
+            .recv()
+            .map_err(|_| anyhow!("PPD PropertiesChanged stream ended"))?;
+        let mut profile = decode_active_profile(&value, config);
+        // Only the profile we settle on counts: skip what's replaced within
+        // `debounce`.
+        while let Ok(newer) = changes.recv_timeout(config.debounce) {
+            let newer = decode_active_profile(&newer, config);
+            debug!(from = ?profile.as_ref().ok(), to = ?newer.as_ref().ok(), "[debounce] superseded");
+            profile = newer;
+        }
+        handle(config, &conn, opts, profile, &mut last);
+    }
+}
+
+/// Forward each `ActiveProfile` change to the returned channel from a thread
+/// of its own; the channel hangs up when the subscription ends.
+fn subscribe(props: &PropertiesProxy, bus: &PpdBus) -> Result<Receiver<OwnedValue>> {
+    let signals = props
+        .receive_properties_changed()
+        .context("subscribe PPD PropertiesChanged")?;
+    let interface = bus.interface.clone();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for signal in signals {
+            if let Some(value) = changed_profile(&signal, &interface)
+                && tx.send(value).is_err()
+            {
+                return;
+            }
+        }
+    });
+    Ok(rx)
+}
+
+/// `ActiveProfile` from a PropertiesChanged signal on `interface`, if it
+/// carries one.
+fn changed_profile(signal: &PropertiesChanged, interface: &str) -> Option<OwnedValue> {
+    let args = match signal.args() {
+        Ok(a) => a,
+        Err(e) => {
+            warn!("signal args decode failed: {e}");
+            return None;
+        }
+    };
+    if args.interface_name() != interface {
+        return None;
+    }
+    let changed: &HashMap<&str, Value> = args.changed_properties();
+    match changed.get("ActiveProfile")?.try_to_owned() {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!("ActiveProfile in PropertiesChanged: {e}");
+            None
+        }
+    }
+}
+
+fn read_active_profile(props: &PropertiesProxy, bus: &PpdBus) -> Result<OwnedValue> {
+    let interface = InterfaceName::try_from(bus.interface.as_str()).context("interface name")?;
+    props
+        .get(interface, "ActiveProfile")
+        .context("read ActiveProfile")
+}
+
+/// The first of `PPD_CANDIDATES` that answers an `ActiveProfile` read,
+/// else the legacy name.
+fn detect_ppd_bus(conn: &Connection) -> PpdBus {
+    let to_bus = |(name, path, interface): (&str, &str, &str)| PpdBus {
+        name: name.to_owned(),
+        path: path.to_owned(),
+        interface: interface.to_owned(),
+    };
+    for candidate in PPD_CANDIDATES {
+        let bus = to_bus(candidate);
+        let answered = PropertiesProxy::new(conn, bus.name.clone(), bus.path.clone())
+            .map_err(anyhow::Error::from)
+            .and_then(|props| read_active_profile(&props, &bus));
+        match answered {
+            Ok(_) => {
+                info!(name = %bus.name, "[ppd] detected D-Bus name");
+                return bus;
+            }
+            Err(e) => debug!(name = %bus.name, "[ppd] probe failed: {e:#}"),
+        }
+    }
+    warn!("[ppd] no power-profiles-daemon answered; trying the legacy name");
+    to_bus(PPD_CANDIDATES[PPD_CANDIDATES.len() - 1])
+}
+
+/// Apply the mode for the decoded `ActiveProfile`, unless it's the one
+/// already in effect. Failures are logged; `last` only moves on success, so
+/// the next change away and back retries.
+fn handle(
+    config: &Config,
+    conn: &Connection,
+    opts: &ApplyOptions,
+    profile: Result<Profile, DecodeError>,
+    last: &mut Option<Profile>,
+) {
+    let p = match profile {
+        Ok(p) => p,
+        Err(DecodeError::NoActiveProfile) => {
+            info!("[profile] PowerProfiles reported no active profile, waiting");
+            return;
+        }
+        Err(e) => {
+            warn!("[profile] ActiveProfile: {e}");
+            return;
+        }
+    };
+    if *last == Some(p) {
+        debug!(profile = ?p, "[profile] unchanged");
+        return;
+    }
+    info!(profile = ?p, "[profile] ActiveProfile");
+    let power = if config.watches_power_source() {
+        read_power_source(conn)
+    } else {
+        PowerSource::Ac
+    };
+    let Some(mode) = block_on(config.choose_mode(p, power)) else {
+        info!(
+            "no mode for profile {}; leaving scheduler unchanged",
+            p.as_config_key()
+        );
+        *last = Some(p);
+        return;
+    };
+    if config.missing_sched_ext == MissingSchedExt::Standby && !sched_ext_available() {
+        warn!("[sched_ext] kernel has no sched_ext support; not applying");
+        return;
+    }
+    match block_on(apply_mode(&mode.for_profile(p), &config.scx, opts)) {
+        Ok(()) => *last = Some(p),
+        Err(e) => warn!(profile = ?p, "apply failed: {:#}", anyhow::Error::from(e)),
+    }
+}
+
+/// UPower's `OnBattery`, read when a mode is about to be picked; a power
+/// source change on its own doesn't trigger an apply in this build.
+fn read_power_source(conn: &Connection) -> PowerSource {
+    let on_battery = Proxy::new(conn, UPOWER_DEST, UPOWER_PATH, UPOWER_IFACE)
+        .and_then(|upower| upower.get_property::<bool>("OnBattery"));
+    match on_battery {
+        Ok(on_battery) => PowerSource::from_on_battery(on_battery),
+        Err(e) => {
+            warn!("[upower] unavailable, assuming AC power: {e:#}");
+            PowerSource::Ac
+        }
+    }
+}
@@ -0,0 +1,1824 @@
+//! Finding, reading and validating the configuration file: the YAML schema,
+//! presets, layering, `${VAR}` expansion and migration from older schemas.
+
+use crate::rt;
+use crate::scx::{ProcessRunner, ScxSettings};
+use crate::{
+    ArgsStyle, ConfigError, Mode, ModeAction, PROFILE_PLACEHOLDER, PowerSource, PpdBus, Profile,
+    ScxSubcommand,
+};
+use serde::de::{Error as _, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use serde_yaml::{Mapping, Value as Yaml};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+const CONFIG_DIR_NAME: &str = "scx-power-sync-dbus";
+const CONFIG_FILE_NAME: &str = "config.yaml";
+const SCXCTL_ENV: &str = "SCX_POWER_SYNC_SCXCTL";
+const POWERPROFILESCTL_ENV: &str = "SCX_POWER_SYNC_POWERPROFILESCTL";
+pub(crate) const DEFAULT_MODE_KEY: &str = "default";
+/// Config schema this build reads, and what `version:` defaults to.
+pub const CONFIG_VERSION: u32 = 1;
+
+impl Mode {
+    /// The mode as a config entry with every field spelled out, for
+    /// `--print-config`.
+    fn to_yaml(&self) -> Yaml {
+        let mut out = Mapping::new();
+        out.insert("action".into(), self.action.as_str().into());
+        out.insert("sched".into(), self.sched.clone().into());
+        out.insert("args".into(), self.args.clone().into());
+        out.insert(
+            "args_style".into(),
+            format!("{:?}", self.args_style).to_lowercase().into(),
+        );
+        out.insert("pre".into(), self.pre.clone().into());
+        out.insert("post".into(), self.post.clone().into());
+        out.insert("epp".into(), optional(self.epp.clone()));
+        out.insert(
+            "force_subcmd".into(),
+            optional(self.force_subcmd.map(ScxSubcommand::as_str)),
+        );
+        out.insert("description".into(), optional(self.description.clone()));
+        out.insert(
+            "env".into(),
+            Yaml::Mapping(
+                self.env
+                    .iter()
+                    .map(|(name, value)| (name.clone().into(), optional(value.clone())))
+                    .collect(),
+            ),
+        );
+        Yaml::Mapping(out)
+    }
+
+    /// Things in `sched`/`args` that are probably typos but might not be,
+    /// so `load_config` only warns about them. Kept to a few patterns that
+    /// are almost never intended.
+    fn suspicious(&self) -> Vec<String> {
+        let mut found = Vec::new();
+        if self.sched.contains(char::is_whitespace) {
+            found.push(format!(
+                "sched {:?} contains whitespace; scheduler flags belong in `args`",
+                self.sched
+            ));
+        }
+        for arg in &self.args {
+            if arg.is_empty() {
+                found.push("args contain an empty argument".to_owned());
+            } else if arg.starts_with('=') {
+                found.push(format!("argument {arg:?} starts with '='"));
+            } else if arg.matches('"').count() % 2 == 1 || arg.matches('\'').count() % 2 == 1 {
+                found.push(format!(
+                    "argument {arg:?} has an unbalanced quote (args are not shell-parsed)"
+                ));
+            }
+        }
+        found
+    }
+}
+
+/// Read ahead of `RawConfig`, so a file written for a newer schema is
+/// reported as such rather than as whatever field no longer fits.
+#[derive(Debug, Deserialize)]
+struct ConfigHeader {
+    #[serde(default)]
+    version: Option<u32>,
+}
+
+fn check_version(version: Option<u32>, path: &Path) -> Result<(), ConfigError> {
+    match version.unwrap_or(CONFIG_VERSION) {
+        CONFIG_VERSION => Ok(()),
+        v if v > CONFIG_VERSION => Err(ConfigError::invalid(
+            path,
+            format!(
+                "{} is config version {v}, but this build only understands version {CONFIG_VERSION}; upgrade scx-power-sync-dbus",
+                path.display()
+            ),
+        )),
+        v => Err(ConfigError::invalid(
+            path,
+            format!(
+                "{}: unknown config version {v} (supported: {CONFIG_VERSION})",
+                path.display()
+            ),
+        )),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RawConfig {
+    modes: Entries<ProfileDefinition>,
+    /// Scheduler for modes that don't name their own.
+    #[serde(default)]
+    sched: Option<String>,
+    #[serde(default = "default_debounce_ms")]
+    debounce_ms: u64,
+    #[serde(default)]
+    min_switch_interval_ms: u64,
+    #[serde(default)]
+    cooldown_after_failures: u32,
+    #[serde(default = "default_failure_cooldown_ms")]
+    failure_cooldown_ms: u64,
+    #[serde(default)]
+    poll_interval_ms: u64,
+    #[serde(default)]
+    reapply_on_resume: bool,
+    #[serde(default)]
+    stop_on_exit: bool,
+    #[serde(default)]
+    missing_sched_ext: MissingSchedExt,
+    /// JSON file the daemon keeps the last applied mode in; `${VAR}` allowed.
+    #[serde(default)]
+    state_file: Option<String>,
+    #[serde(default = "default_shutdown_timeout_ms")]
+    shutdown_timeout_ms: u64,
+    #[serde(default = "default_startup_wait_ms")]
+    startup_wait_ms: u64,
+    #[serde(default)]
+    startup_delay_ms: u64,
+    #[serde(default = "default_history_size")]
+    history_size: usize,
+    #[serde(default = "default_true")]
+    require_all_profiles: bool,
+    #[serde(default)]
+    scxctl_path: Option<PathBuf>,
+    /// Put before the subcommand on every scxctl call; `${VAR}` allowed.
+    #[serde(default)]
+    scxctl_global_args: Vec<String>,
+    #[serde(default)]
+    powerprofilesctl_path: Option<PathBuf>,
+    #[serde(default = "default_retries")]
+    retries: u32,
+    #[serde(default = "default_retry_delay_ms")]
+    retry_delay_ms: u64,
+    #[serde(default = "default_scxctl_timeout_ms")]
+    scxctl_timeout_ms: u64,
+    #[serde(default)]
+    verify_switch: bool,
+    #[serde(default = "default_verify_switch_timeout_ms")]
+    verify_switch_timeout_ms: u64,
+    #[serde(default)]
+    source: SourceKind,
+    #[serde(default)]
+    bus: BusKind,
+    /// TuneD profile name -> config key; replaces the built-in map when set.
+    #[serde(default)]
+    tuned_profiles: Option<HashMap<String, String>>,
+    /// Extra power-profiles-daemon names -> config key.
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+    /// Where power-profiles-daemon lives; detected when unset.
+    #[serde(default)]
+    ppd_dbus: Option<PpdBus>,
+    /// Follow the profile underneath power-profiles-daemon's holds.
+    #[serde(default)]
+    ignore_holds: bool,
+    /// Named alternatives to `modes`, same shape.
+    #[serde(default)]
+    presets: Entries<Entries<ProfileDefinition>>,
+    /// Preset selected at startup; none means just `modes`.
+    #[serde(default)]
+    preset: Option<String>,
+    /// Modes replacing `performance` while PPD reports it degraded, keyed by
+    /// the reason it gives (or `default`).
+    #[serde(default)]
+    performance_degraded: Entries<ProfileDefinition>,
+}
+
+/// A YAML map kept as its entries in file order, repeats included, so
+/// `load_config` can report a key given twice; deserialized straight into a
+/// `HashMap` the last one would silently win.
+#[derive(Debug)]
+struct Entries<V>(Vec<(String, V)>);
+
+impl<V> Default for Entries<V> {
+    fn default() -> Self {
+        Entries(Vec::new())
+    }
+}
+
+impl<'de, V: Deserialize<'de>> Deserialize<'de> for Entries<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct EntriesVisitor<V>(PhantomData<V>);
+
+        impl<'de, V: Deserialize<'de>> Visitor<'de> for EntriesVisitor<V> {
+            type Value = Entries<V>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> std::result::Result<Self::Value, A::Error> {
+                let mut entries = Vec::new();
+                let mut merged = Vec::new();
+                while let Some(key) = map.next_key::<String>()? {
+                    if key == MERGE_KEY {
+                        match map.next_value()? {
+                            Merge::One(Entries(e)) => merged.extend(e),
+                            Merge::Many(list) => merged.extend(list.into_iter().flat_map(|e| e.0)),
+                        }
+                    } else {
+                        entries.push((key, map.next_value()?));
+                    }
+                }
+                // As YAML has it: keys written out override merged ones, and
+                // among merged maps the first to give a key wins. Neither
+                // counts as a repeat.
+                for (key, value) in merged {
+                    if !entries.iter().any(|(k, _)| *k == key) {
+                        entries.push((key, value));
+                    }
+                }
+                Ok(Entries(entries))
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor(PhantomData))
+    }
+}
+
+/// YAML's merge key: `<<: *anchor` (or a list of anchors) pulls the entries
+/// of those maps in under the ones written alongside it.
+const MERGE_KEY: &str = "<<";
+
+/// What may follow `<<` in an `Entries` map.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Merge<V> {
+    One(Entries<V>),
+    Many(Vec<Entries<V>>),
+}
+
+/// Which daemon reports the active power profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    /// power-profiles-daemon (`net.hadess.PowerProfiles`).
+    #[default]
+    Ppd,
+    /// TuneD (`com.redhat.tuned`).
+    Tuned,
+}
+
+/// The message bus the profile source is reached on. The daemon's own
+/// `org.scx.PowerSync` name and the UPower watch use the same connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BusKind {
+    #[default]
+    System,
+    /// The user's session bus: containers, development, mock services.
+    Session,
+}
+
+impl BusKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BusKind::System => "system",
+            BusKind::Session => "session",
+        }
+    }
+}
+
+/// What the daemon does when `sched_ext_available` says the kernel can't
+/// run sched_ext schedulers at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingSchedExt {
+    /// Log it once and ignore profile changes until support shows up.
+    #[default]
+    Standby,
+    /// Refuse to start.
+    Exit,
+    /// Don't check; every apply goes to scxctl as usual.
+    Ignore,
+}
+
+fn default_tuned_profiles() -> HashMap<String, Profile> {
+    [
+        ("throughput-performance", Profile::Performance),
+        ("latency-performance", Profile::Performance),
+        ("balanced", Profile::Balanced),
+        ("powersave", Profile::PowerSaver),
+    ]
+    .into_iter()
+    .map(|(name, profile)| (name.to_owned(), profile))
+    .collect()
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_retry_delay_ms() -> u64 {
+    500
+}
+
+fn default_scxctl_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_verify_switch_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_failure_cooldown_ms() -> u64 {
+    60_000
+}
+
+fn default_shutdown_timeout_ms() -> u64 {
+    15_000
+}
+
+fn default_startup_wait_ms() -> u64 {
+    30_000
+}
+
+fn default_history_size() -> usize {
+    20
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_debounce_ms() -> u64 {
+    300
+}
+
+#[derive(Debug, Deserialize)]
+struct ModeDefinition {
+    /// `false` keeps the entry but makes it a no-op; `sched`/`args` may then
+    /// be left out.
+    #[serde(default = "default_true")]
+    enabled: bool,
+    #[serde(default)]
+    action: ModeAction,
+    #[serde(default)]
+    sched: Option<String>,
+    #[serde(default)]
+    args: Option<ArgsDefinition>,
+    #[serde(default)]
+    pre: Option<HookDefinition>,
+    #[serde(default)]
+    post: Option<HookDefinition>,
+    #[serde(default)]
+    epp: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    force_subcmd: Option<ScxSubcommand>,
+    #[serde(default)]
+    args_style: ArgsStyle,
+    /// `~` or `""` unsets the variable.
+    #[serde(default)]
+    env: BTreeMap<String, Option<String>>,
+}
+
+/// A profile's entry: one mode, separate `ac` and `battery` modes chosen by
+/// UPower's `OnBattery`, or named `choices` picked by a `selector` command.
+#[derive(Debug)]
+enum ProfileDefinition {
+    Single(ModeDefinition),
+    ByPowerSource {
+        ac: ModeDefinition,
+        battery: ModeDefinition,
+    },
+    Selected {
+        selector: String,
+        selector_timeout_ms: u64,
+        choices: HashMap<String, ModeDefinition>,
+    },
+}
+
+fn default_selector_timeout_ms() -> u64 {
+    2_000
+}
+
+impl<'de> Deserialize<'de> for ProfileDefinition {
+    // Hand-rolled instead of `#[serde(untagged)]` so a typo in an ordinary
+    // mode still reports the offending field rather than "did not match any
+    // variant".
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Split {
+            ac: ModeDefinition,
+            battery: ModeDefinition,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Selected {
+            selector: String,
+            #[serde(default = "default_selector_timeout_ms")]
+            selector_timeout_ms: u64,
+            choices: HashMap<String, ModeDefinition>,
+        }
+
+        let mut value = serde_yaml::Value::deserialize(deserializer)?;
+        // serde_yaml resolves anchors but leaves `<<` as an ordinary key.
+        value.apply_merge().map_err(D::Error::custom)?;
+        if value
+            .as_mapping()
+            .is_some_and(|m| m.contains_key("selector") || m.contains_key("choices"))
+        {
+            let Selected {
+                selector,
+                selector_timeout_ms,
+                choices,
+            } = serde_yaml::from_value(value).map_err(D::Error::custom)?;
+            return Ok(ProfileDefinition::Selected {
+                selector,
+                selector_timeout_ms,
+                choices,
+            });
+        }
+        let split = value
+            .as_mapping()
+            .is_some_and(|m| m.contains_key("ac") || m.contains_key("battery"));
+        if split {
+            let Split { ac, battery } = serde_yaml::from_value(value).map_err(D::Error::custom)?;
+            Ok(ProfileDefinition::ByPowerSource { ac, battery })
+        } else {
+            serde_yaml::from_value(value)
+                .map(ProfileDefinition::Single)
+                .map_err(D::Error::custom)
+        }
+    }
+}
+
+/// `args` may be written either as a single string (the original form) or as
+/// a YAML sequence of tokens.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ArgsDefinition {
+    Joined(String),
+    List(Vec<String>),
+}
+
+impl ArgsDefinition {
+    /// Check placeholders in what the user wrote, then expand `${VAR}`
+    /// references and tokenize; a variable's value is never taken for a
+    /// placeholder. The joined form is split after expansion, so a variable
+    /// may carry several arguments.
+    fn into_tokens(self) -> Result<Vec<String>, String> {
+        match self {
+            ArgsDefinition::Joined(s) => {
+                check_placeholders(&s)?;
+                Ok(expand_env(&s)?
+                    .split_whitespace()
+                    .map(str::to_owned)
+                    .collect())
+            }
+            ArgsDefinition::List(v) => v
+                .iter()
+                .map(|arg| {
+                    check_placeholders(arg)?;
+                    expand_env(arg)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Reject `{name}` placeholders other than `{profile}`. Braces around
+/// anything that isn't a plain name, e.g. `{1,2}`, are left alone, and so
+/// are `${VAR}` references.
+fn check_placeholders(arg: &str) -> Result<(), String> {
+    let mut rest = arg;
+    while let Some(start) = rest.find('{') {
+        let reference = rest[..start].ends_with('$');
+        let after = &rest[start + 1..];
+        if reference {
+            rest = after;
+            continue;
+        }
+        let Some(end) = after.find('}') else {
+            break;
+        };
+        let name = &after[..end];
+        let is_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if is_name && name != "profile" {
+            return Err(format!(
+                "unknown placeholder '{{{name}}}' in '{arg}' (only {PROFILE_PLACEHOLDER} is supported)"
+            ));
+        }
+        rest = after;
+    }
+    Ok(())
+}
+
+/// Replace `${VAR}` with the variable's value and `${VAR:-default}` with its
+/// value or `default` when it is unset or empty. An unset `${VAR}` is an error.
+fn expand_env(input: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| format!("unterminated '${{' in '{input}'"))?;
+        let reference = &after[..end];
+        let (name, fallback) = match reference.split_once(":-") {
+            Some((name, fallback)) => (name, Some(fallback)),
+            None => (reference, None),
+        };
+        if name.is_empty() {
+            return Err(format!("empty variable name in '{input}'"));
+        }
+        match (env::var(name), fallback) {
+            (Ok(value), Some(fallback)) if value.is_empty() => out.push_str(fallback),
+            (Ok(value), _) => out.push_str(&value),
+            (Err(_), Some(fallback)) => out.push_str(fallback),
+            (Err(env::VarError::NotPresent), None) => {
+                return Err(format!(
+                    "environment variable {name} is not set (use ${{{name}:-default}} for a fallback)"
+                ));
+            }
+            (Err(env::VarError::NotUnicode(_)), None) => {
+                return Err(format!("environment variable {name} is not valid UTF-8"));
+            }
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// A hook is either one shell command or a list of them, run in order.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum HookDefinition {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl HookDefinition {
+    fn into_commands(self) -> Vec<String> {
+        match self {
+            HookDefinition::One(cmd) => vec![cmd],
+            HookDefinition::Many(cmds) => cmds,
+        }
+    }
+}
+
+impl ModeDefinition {
+    /// `None` for a disabled entry.
+    fn into_mode(self) -> Result<Option<Mode>, String> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let (sched, args) = match self.action {
+            ModeAction::Stop => {
+                if self.sched.is_some() || self.args.is_some() || self.force_subcmd.is_some() {
+                    return Err(
+                        "`sched`, `args` and `force_subcmd` don't apply to `action: stop`"
+                            .to_owned(),
+                    );
+                }
+                (String::new(), Vec::new())
+            }
+            ModeAction::Run => {
+                let sched = self
+                    .sched
+                    .ok_or("missing field `sched` (and no top-level `sched`)")?;
+                let args = self.args.ok_or("missing field `args`")?;
+                let sched = expand_env(&sched).map_err(|e| format!("sched: {e}"))?;
+                if sched.trim().is_empty() {
+                    return Err("`sched` is empty".to_owned());
+                }
+                (sched, args.into_tokens().map_err(|e| format!("args: {e}"))?)
+            }
+        };
+        Ok(Some(Mode {
+            action: self.action,
+            sched,
+            args,
+            pre: self
+                .pre
+                .map(HookDefinition::into_commands)
+                .unwrap_or_default(),
+            post: self
+                .post
+                .map(HookDefinition::into_commands)
+                .unwrap_or_default(),
+            epp: self
+                .epp
+                .map(|epp| expand_env(&epp))
+                .transpose()
+                .map_err(|e| format!("epp: {e}"))?,
+            description: self.description,
+            force_subcmd: self.force_subcmd,
+            args_style: self.args_style,
+            env: self
+                .env
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = value
+                        .filter(|v| !v.is_empty())
+                        .map(|v| expand_env(&v))
+                        .transpose()
+                        .map_err(|e| format!("env {name}: {e}"))?;
+                    Ok((name, value))
+                })
+                .collect::<Result<_, String>>()?,
+        }))
+    }
+}
+
+/// The mode(s) configured for one profile. `None` marks an entry written
+/// with `enabled: false`: the profile is configured, but leaves the
+/// scheduler alone.
+#[derive(Debug, Clone)]
+pub enum ModeSet {
+    Single(Option<Mode>),
+    ByPowerSource {
+        ac: Option<Mode>,
+        battery: Option<Mode>,
+    },
+    Selected(Selector),
+}
+
+/// A command whose stdout names one of `choices`.
+#[derive(Debug, Clone)]
+pub struct Selector {
+    /// Run through `/bin/sh -c`.
+    pub command: String,
+    /// Past this the command is killed and `default` is used.
+    pub timeout: Duration,
+    /// Always holds `default`, used when the command fails or prints a key
+    /// that isn't here.
+    pub choices: HashMap<String, Option<Mode>>,
+}
+
+impl Selector {
+    /// Run the command and look up its answer, falling back to `default`.
+    pub async fn choose(&self) -> Option<&Mode> {
+        let key = match run_selector(&self.command, self.timeout).await {
+            Ok(key) if self.choices.contains_key(&key) => {
+                info!(choice = %key, "[selector]");
+                key
+            }
+            Ok(key) => {
+                warn!("[selector] printed unknown choice {key:?}; using '{DEFAULT_MODE_KEY}'");
+                DEFAULT_MODE_KEY.to_owned()
+            }
+            Err(e) => {
+                warn!("[selector] {e}; using '{DEFAULT_MODE_KEY}'");
+                DEFAULT_MODE_KEY.to_owned()
+            }
+        };
+        self.choices.get(&key).and_then(Option::as_ref)
+    }
+
+    fn fallback(&self) -> Option<&Mode> {
+        self.choices.get(DEFAULT_MODE_KEY).and_then(Option::as_ref)
+    }
+}
+
+impl ModeSet {
+    /// The mode to use on `power`; `None` if that entry is disabled. For a
+    /// selector this is its `default` choice, since nothing is run here; see
+    /// `choose`.
+    pub fn select(&self, power: PowerSource) -> Option<&Mode> {
+        match (self, power) {
+            (ModeSet::Single(mode), _) => mode.as_ref(),
+            (ModeSet::ByPowerSource { ac, .. }, PowerSource::Ac) => ac.as_ref(),
+            (ModeSet::ByPowerSource { battery, .. }, PowerSource::Battery) => battery.as_ref(),
+            (ModeSet::Selected(selector), _) => selector.fallback(),
+        }
+    }
+
+    /// Like `select`, but runs the selector command if there is one.
+    pub async fn choose(&self, power: PowerSource) -> Option<&Mode> {
+        match self {
+            ModeSet::Selected(selector) => selector.choose().await,
+            _ => self.select(power),
+        }
+    }
+
+    /// One-line rendering of every variant.
+    pub fn describe(&self) -> String {
+        fn one(mode: &Option<Mode>) -> String {
+            mode.as_ref()
+                .map_or_else(|| "disabled".to_owned(), Mode::describe)
+        }
+        match self {
+            ModeSet::Single(mode) => one(mode),
+            ModeSet::ByPowerSource { ac, battery } => {
+                format!("ac: {}; battery: {}", one(ac), one(battery))
+            }
+            ModeSet::Selected(selector) => {
+                let mut keys: Vec<&String> = selector.choices.keys().collect();
+                keys.sort();
+                let choices: Vec<String> = keys
+                    .into_iter()
+                    .map(|key| format!("{key}: {}", one(&selector.choices[key])))
+                    .collect();
+                format!("selector `{}`: {}", selector.command, choices.join("; "))
+            }
+        }
+    }
+
+    /// The set as a config entry; a disabled mode is `enabled: false`.
+    fn to_yaml(&self) -> Yaml {
+        fn one(mode: &Option<Mode>) -> Yaml {
+            match mode {
+                Some(mode) => mode.to_yaml(),
+                None => Yaml::Mapping(Mapping::from_iter([("enabled".into(), false.into())])),
+            }
+        }
+        let mut out = Mapping::new();
+        match self {
+            ModeSet::Single(mode) => return one(mode),
+            ModeSet::ByPowerSource { ac, battery } => {
+                out.insert("ac".into(), one(ac));
+                out.insert("battery".into(), one(battery));
+            }
+            ModeSet::Selected(selector) => {
+                let mut keys: Vec<&String> = selector.choices.keys().collect();
+                keys.sort();
+                let choices = keys
+                    .into_iter()
+                    .map(|key| (key.as_str().into(), one(&selector.choices[key])))
+                    .collect();
+                out.insert("selector".into(), selector.command.clone().into());
+                out.insert("selector_timeout_ms".into(), millis(selector.timeout));
+                out.insert("choices".into(), Yaml::Mapping(choices));
+            }
+        }
+        Yaml::Mapping(out)
+    }
+
+    /// Every enabled mode in the set, for validation.
+    pub fn modes(&self) -> Vec<&Mode> {
+        match self {
+            ModeSet::Single(mode) => mode.iter().collect(),
+            ModeSet::ByPowerSource { ac, battery } => ac.iter().chain(battery).collect(),
+            ModeSet::Selected(selector) => selector.choices.values().flatten().collect(),
+        }
+    }
+}
+
+impl ProfileDefinition {
+    /// Give every mode in this entry without a `sched` of its own `sched`,
+    /// except `action: stop` ones, which take none.
+    fn inherit_sched(&mut self, sched: &str) {
+        let defs: Vec<&mut ModeDefinition> = match self {
+            ProfileDefinition::Single(def) => vec![def],
+            ProfileDefinition::ByPowerSource { ac, battery } => vec![ac, battery],
+            ProfileDefinition::Selected { choices, .. } => choices.values_mut().collect(),
+        };
+        for def in defs.into_iter().filter(|def| def.action == ModeAction::Run) {
+            def.sched.get_or_insert_with(|| sched.to_owned());
+        }
+    }
+}
+
+impl TryFrom<ProfileDefinition> for ModeSet {
+    type Error = String;
+
+    fn try_from(def: ProfileDefinition) -> Result<Self, String> {
+        Ok(match def {
+            ProfileDefinition::Single(mode) => ModeSet::Single(mode.into_mode()?),
+            ProfileDefinition::ByPowerSource { ac, battery } => ModeSet::ByPowerSource {
+                ac: ac.into_mode().map_err(|e| format!("ac: {e}"))?,
+                battery: battery.into_mode().map_err(|e| format!("battery: {e}"))?,
+            },
+            ProfileDefinition::Selected {
+                selector,
+                selector_timeout_ms,
+                choices,
+            } => {
+                if !choices.contains_key(DEFAULT_MODE_KEY) {
+                    return Err(format!(
+                        "choices need a '{DEFAULT_MODE_KEY}' entry for when the selector fails"
+                    ));
+                }
+                let choices = choices
+                    .into_iter()
+                    .map(|(key, def)| {
+                        let mode = def
+                            .into_mode()
+                            .map_err(|e| format!("choice '{key}': {e}"))?;
+                        Ok((key, mode))
+                    })
+                    .collect::<Result<_, String>>()?;
+                ModeSet::Selected(Selector {
+                    command: selector,
+                    timeout: Duration::from_millis(selector_timeout_ms),
+                    choices,
+                })
+            }
+        })
+    }
+}
+
+/// A loaded and validated configuration; see `load_config`.
+#[non_exhaustive]
+pub struct Config {
+    /// File this was loaded from; the most specific one when merged.
+    pub path: PathBuf,
+    /// Every file read for this config, least specific first. Just `path`
+    /// unless it came from `load_merged_config`.
+    pub layers: Vec<PathBuf>,
+    /// Modes for profiles that have their own entry.
+    pub modes: HashMap<Profile, ModeSet>,
+    /// `modes.default`: used for any profile without its own entry.
+    pub default: Option<ModeSet>,
+    /// Quiet period after an ActiveProfile change before applying it.
+    pub debounce: Duration,
+    /// Floor on the time between two applied modes; zero disables it.
+    pub min_switch_interval: Duration,
+    /// After this many applies in a row have failed, the daemon stops
+    /// applying for `failure_cooldown`; zero disables the cooldown.
+    pub cooldown_after_failures: u32,
+    pub failure_cooldown: Duration,
+    /// How often the daemon re-reads the active profile in case a change
+    /// signal was lost; zero disables polling.
+    pub poll_interval: Duration,
+    /// Re-read the active profile and re-apply its mode when logind reports
+    /// a resume from suspend.
+    pub reapply_on_resume: bool,
+    /// Run `scxctl stop` when the daemon is terminated.
+    pub stop_on_exit: bool,
+    /// What the daemon does on a kernel without sched_ext.
+    pub missing_sched_ext: MissingSchedExt,
+    /// Where the daemon writes the last applied mode, if anywhere.
+    pub state_file: Option<PathBuf>,
+    /// How long termination waits for an in-flight apply before aborting it.
+    pub shutdown_timeout: Duration,
+    /// How long the daemon waits at startup for the profile source to
+    /// appear on the bus; zero disables the wait.
+    pub startup_wait: Duration,
+    /// Pause before the startup read and apply, once the change signals are
+    /// subscribed, to let other boot-time services settle; zero disables it.
+    pub startup_delay: Duration,
+    /// How many finished applies the daemon remembers for its `History`
+    /// D-Bus method; zero keeps none.
+    pub history_size: usize,
+    /// How scxctl is invoked.
+    pub scx: ScxSettings,
+    /// Only checked for at startup; bare name or absolute path.
+    pub powerprofilesctl: PathBuf,
+    /// Where the active profile is read from.
+    pub source: SourceKind,
+    /// Which D-Bus the daemon connects to for it.
+    pub bus: BusKind,
+    /// Only consulted when `source` is TuneD.
+    pub tuned_profiles: HashMap<String, Profile>,
+    /// power-profiles-daemon names beyond the three built-in ones.
+    pub aliases: HashMap<String, Profile>,
+    /// Set by `ppd_dbus`; `None` leaves it to the daemon to detect.
+    pub ppd_dbus: Option<PpdBus>,
+    /// While an application holds a profile (`ActiveProfileHolds`), keep the
+    /// mode of the profile that was active before the hold.
+    pub ignore_holds: bool,
+    /// Named mode tables from `presets:`.
+    pub presets: HashMap<String, Preset>,
+    /// Preset `mode_for` resolves through; see `select_preset`.
+    pub active_preset: Option<String>,
+    /// Profiles looked up as another profile's mode; empty unless the caller
+    /// fills it (the daemon's `--profile-map`). See `mapped_profile`.
+    pub profile_map: HashMap<Profile, Profile>,
+    /// `performance_degraded`: PPD's degradation reason (or `default`) ->
+    /// the mode used for `performance` meanwhile. See `degraded_mode_set`.
+    pub performance_degraded: HashMap<String, ModeSet>,
+}
+
+/// A mode table: per-profile entries plus an optional `default`. Used for
+/// each entry under `presets:`.
+#[derive(Debug, Clone, Default)]
+pub struct Preset {
+    pub modes: HashMap<Profile, ModeSet>,
+    pub default: Option<ModeSet>,
+}
+
+impl Preset {
+    fn get(&self, profile: Profile) -> Option<&ModeSet> {
+        self.modes.get(&profile).or(self.default.as_ref())
+    }
+}
+
+impl Config {
+    /// Resolve `profile` through the active preset: see `mode_set_in`.
+    pub fn mode_set_for(&self, profile: Profile) -> Option<&ModeSet> {
+        self.mode_set_in(profile, self.active_preset.as_deref())
+    }
+
+    /// The entry for `profile` in `preset` (or that preset's `default`), else
+    /// the top-level entry, else the top-level `default`.
+    pub fn mode_set_in(&self, profile: Profile, preset: Option<&str>) -> Option<&ModeSet> {
+        preset
+            .and_then(|name| self.presets.get(name))
+            .and_then(|preset| preset.get(profile))
+            .or_else(|| self.modes.get(&profile))
+            .or(self.default.as_ref())
+    }
+
+    /// The `performance_degraded` entry for PPD's `PerformanceDegraded`
+    /// value: the first reason in it (it may list several, comma-separated)
+    /// that has an entry, else `default`. `None` while not degraded.
+    pub fn degraded_mode_set(&self, reasons: &str) -> Option<&ModeSet> {
+        if reasons.trim().is_empty() {
+            return None;
+        }
+        reasons
+            .split(',')
+            .find_map(|reason| self.performance_degraded.get(reason.trim()))
+            .or_else(|| self.performance_degraded.get(DEFAULT_MODE_KEY))
+    }
+
+    /// The profile whose mode `p` uses: `p` itself unless `profile_map`
+    /// redirects it.
+    pub fn mapped_profile(&self, p: Profile) -> Profile {
+        self.profile_map.get(&p).copied().unwrap_or(p)
+    }
+
+    /// Make `name` the active preset, or go back to plain `modes` with `None`.
+    pub fn select_preset(&mut self, name: Option<&str>) -> Result<(), ConfigError> {
+        if let Some(name) = name
+            && !self.presets.contains_key(name)
+        {
+            return Err(ConfigError::UnknownPreset(name.to_owned()));
+        }
+        self.active_preset = name.map(str::to_owned);
+        Ok(())
+    }
+
+    /// Preset names, sorted.
+    pub fn preset_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.presets.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Every configured mode set with a label (`balanced`, `gaming.default`).
+    pub(crate) fn labeled_mode_sets(&self) -> Vec<(String, &ModeSet)> {
+        let mut sets = labeled_table("", &self.modes, self.default.as_ref());
+        for name in self.preset_names() {
+            let preset = &self.presets[name];
+            sets.extend(labeled_table(
+                &format!("{name}."),
+                &preset.modes,
+                preset.default.as_ref(),
+            ));
+        }
+        let mut degraded: Vec<_> = self.performance_degraded.iter().collect();
+        degraded.sort_by_key(|(reason, _)| *reason);
+        sets.extend(
+            degraded
+                .into_iter()
+                .map(|(reason, set)| (format!("performance_degraded.{reason}"), set)),
+        );
+        sets
+    }
+
+    /// The mode to apply for `profile` on `power`, if any is configured and
+    /// enabled.
+    pub fn mode_for(&self, profile: Profile, power: PowerSource) -> Option<&Mode> {
+        self.mode_set_for(profile)?.select(power)
+    }
+
+    /// Profiles with their own entry under `modes` or any preset (entries
+    /// reached only through a `default` don't count), in `Profile::all` order.
+    pub fn explicit_profiles(&self) -> Vec<Profile> {
+        Profile::all()
+            .into_iter()
+            .filter(|p| {
+                self.modes.contains_key(p)
+                    || self
+                        .presets
+                        .values()
+                        .any(|preset| preset.modes.contains_key(p))
+            })
+            .collect()
+    }
+
+    /// `mode_for`, but running the profile's selector if it has one.
+    pub async fn choose_mode(&self, profile: Profile, power: PowerSource) -> Option<&Mode> {
+        self.mode_set_for(profile)?.choose(power).await
+    }
+
+    /// Map a name reported by the profile source to a `Profile`.
+    pub fn resolve_profile(&self, name: &str) -> Result<Profile, ConfigError> {
+        let found = match self.source {
+            SourceKind::Ppd => Profile::from_str(name)
+                .ok()
+                .or_else(|| self.aliases.get(name).copied()),
+            SourceKind::Tuned => self.tuned_profiles.get(name).copied(),
+        };
+        found.ok_or_else(|| ConfigError::UnmappedProfile {
+            name: name.to_owned(),
+            from: self.source,
+        })
+    }
+
+    /// Human-readable listing of what each profile resolves to.
+    pub fn summary(&self) -> String {
+        let mut out = format!("{}: OK\n", self.path.display());
+        if self.layers.len() > 1 {
+            let layers: Vec<String> = self
+                .layers
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect();
+            out.push_str(&format!("  merged from: {}\n", layers.join(", ")));
+        }
+        out.push_str(&format!("  source: {:?}\n", self.source).to_lowercase());
+        if self.source == SourceKind::Tuned {
+            let mut names: Vec<_> = self.tuned_profiles.iter().collect();
+            names.sort_by_key(|(name, _)| *name);
+            for (name, profile) in names {
+                out.push_str(&format!("  tuned {name} -> {}\n", profile.as_config_key()));
+            }
+        } else {
+            if let Some(bus) = &self.ppd_dbus {
+                out.push_str(&format!(
+                    "  ppd_dbus: {} {} {}\n",
+                    bus.name, bus.path, bus.interface
+                ));
+            }
+            if self.ignore_holds {
+                out.push_str("  ignore_holds: profile holds are ignored\n");
+            }
+            let mut names: Vec<_> = self.aliases.iter().collect();
+            names.sort_by_key(|(name, _)| *name);
+            for (name, profile) in names {
+                out.push_str(&format!("  alias {name} -> {}\n", profile.as_config_key()));
+            }
+        }
+        self.summarize_table(&mut out, None, "  ");
+        for name in self.preset_names() {
+            let active = if self.active_preset.as_deref() == Some(name) {
+                " (active)"
+            } else {
+                ""
+            };
+            out.push_str(&format!("  preset {name}{active}:\n"));
+            self.summarize_table(&mut out, Some(name), "    ");
+        }
+        let mut degraded: Vec<_> = self.performance_degraded.iter().collect();
+        degraded.sort_by_key(|(reason, _)| *reason);
+        for (reason, set) in degraded {
+            out.push_str(&format!(
+                "  performance degraded ({reason}): {}\n",
+                set.describe()
+            ));
+        }
+        out
+    }
+
+    fn summarize_table(&self, out: &mut String, preset: Option<&str>, indent: &str) {
+        let preset_table = preset.and_then(|name| self.presets.get(name));
+        for profile in Profile::all() {
+            let key = profile.as_config_key();
+            let own = preset_table.and_then(|t| t.modes.get(&profile));
+            let preset_default = preset_table.and_then(|t| t.default.as_ref());
+            let line = match (own, preset_default, self.modes.get(&profile), &self.default) {
+                (Some(set), ..) => set.describe(),
+                (None, Some(set), ..) => format!("{} (preset default)", set.describe()),
+                (None, None, Some(set), _) if preset.is_some() => {
+                    format!("{} (from modes)", set.describe())
+                }
+                (None, None, Some(set), _) => set.describe(),
+                (None, None, None, Some(set)) => format!("{} (default)", set.describe()),
+                (None, None, None, None) => "not configured; scheduler left unchanged".to_owned(),
+            };
+            out.push_str(&format!("{indent}{key:<12} {line}\n"));
+        }
+    }
+
+    /// Everything the daemon would run with, shaped like a config file:
+    /// defaults filled in, `${VAR}`s expanded, layers merged, and under
+    /// `modes` the entry each profile ends up with once the active preset
+    /// and `default` are taken into account. For `--print-config`.
+    pub fn effective(&self) -> Yaml {
+        let path = |p: &Path| Yaml::from(p.display().to_string());
+        let names = |map: &HashMap<String, Profile>| {
+            let mut names: Vec<_> = map.iter().collect();
+            names.sort_by_key(|(name, _)| *name);
+            Yaml::Mapping(
+                names
+                    .into_iter()
+                    .map(|(name, p)| (name.as_str().into(), p.as_config_key().into()))
+                    .collect(),
+            )
+        };
+        let table = |preset: Option<&str>| {
+            Yaml::Mapping(
+                Profile::all()
+                    .into_iter()
+                    .map(|p| {
+                        let entry = self
+                            .mode_set_in(p, preset)
+                            .map_or(Yaml::Null, ModeSet::to_yaml);
+                        (p.as_config_key().into(), entry)
+                    })
+                    .collect(),
+            )
+        };
+
+        let mut out = Mapping::new();
+        out.insert("path".into(), path(&self.path));
+        if self.layers.len() > 1 {
+            out.insert(
+                "layers".into(),
+                Yaml::Sequence(self.layers.iter().map(|p| path(p)).collect()),
+            );
+        }
+        out.insert("version".into(), CONFIG_VERSION.into());
+        out.insert(
+            "source".into(),
+            format!("{:?}", self.source).to_lowercase().into(),
+        );
+        out.insert("bus".into(), self.bus.as_str().into());
+        if let Some(bus) = &self.ppd_dbus {
+            out.insert(
+                "ppd_dbus".into(),
+                Yaml::Mapping(Mapping::from_iter([
+                    ("name".into(), bus.name.clone().into()),
+                    ("path".into(), bus.path.clone().into()),
+                    ("interface".into(), bus.interface.clone().into()),
+                ])),
+            );
+        }
+        out.insert("ignore_holds".into(), self.ignore_holds.into());
+        out.insert("aliases".into(), names(&self.aliases));
+        if self.source == SourceKind::Tuned {
+            out.insert("tuned_profiles".into(), names(&self.tuned_profiles));
+        }
+        out.insert("debounce_ms".into(), millis(self.debounce));
+        out.insert(
+            "min_switch_interval_ms".into(),
+            millis(self.min_switch_interval),
+        );
+        out.insert(
+            "cooldown_after_failures".into(),
+            self.cooldown_after_failures.into(),
+        );
+        out.insert("failure_cooldown_ms".into(), millis(self.failure_cooldown));
+        out.insert("poll_interval_ms".into(), millis(self.poll_interval));
+        out.insert("reapply_on_resume".into(), self.reapply_on_resume.into());
+        out.insert("stop_on_exit".into(), self.stop_on_exit.into());
+        out.insert(
+            "missing_sched_ext".into(),
+            format!("{:?}", self.missing_sched_ext)
+                .to_lowercase()
+                .into(),
+        );
+        out.insert(
+            "state_file".into(),
+            optional(self.state_file.as_deref().map(path)),
+        );
+        out.insert("shutdown_timeout_ms".into(), millis(self.shutdown_timeout));
+        out.insert("startup_wait_ms".into(), millis(self.startup_wait));
+        out.insert("startup_delay_ms".into(), millis(self.startup_delay));
+        out.insert("history_size".into(), self.history_size.into());
+        out.insert("scxctl_path".into(), path(&self.scx.bin));
+        out.insert(
+            "scxctl_global_args".into(),
+            self.scx.global_args.clone().into(),
+        );
+        out.insert("powerprofilesctl_path".into(), path(&self.powerprofilesctl));
+        out.insert("retries".into(), self.scx.retries.into());
+        out.insert("retry_delay_ms".into(), millis(self.scx.retry_delay));
+        out.insert("scxctl_timeout_ms".into(), millis(self.scx.timeout));
+        out.insert("verify_switch".into(), self.scx.verify.is_some().into());
+        if let Some(limit) = self.scx.verify {
+            out.insert("verify_switch_timeout_ms".into(), millis(limit));
+        }
+        if !self.profile_map.is_empty() {
+            let map = Profile::all()
+                .into_iter()
+                .filter_map(|from| {
+                    let to = self.profile_map.get(&from)?;
+                    Some((from.as_config_key().into(), to.as_config_key().into()))
+                })
+                .collect();
+            out.insert("profile_map".into(), Yaml::Mapping(map));
+        }
+        out.insert("preset".into(), optional(self.active_preset.clone()));
+        out.insert("modes".into(), table(self.active_preset.as_deref()));
+        if !self.presets.is_empty() {
+            let presets = self
+                .preset_names()
+                .into_iter()
+                .map(|name| (name.into(), table(Some(name))))
+                .collect();
+            out.insert("presets".into(), Yaml::Mapping(presets));
+        }
+        if !self.performance_degraded.is_empty() {
+            let mut reasons: Vec<_> = self.performance_degraded.iter().collect();
+            reasons.sort_by_key(|(reason, _)| *reason);
+            let reasons = reasons
+                .into_iter()
+                .map(|(reason, set)| (reason.as_str().into(), set.to_yaml()))
+                .collect();
+            out.insert("performance_degraded".into(), Yaml::Mapping(reasons));
+        }
+        Yaml::Mapping(out)
+    }
+
+    /// UPower is only subscribed to when some profile has ac/battery variants.
+    pub fn watches_power_source(&self) -> bool {
+        self.labeled_mode_sets()
+            .iter()
+            .any(|(_, set)| matches!(set, ModeSet::ByPowerSource { .. }))
+    }
+}
+
+/// `null` for `None`.
+fn optional<T: Into<Yaml>>(value: Option<T>) -> Yaml {
+    value.map_or(Yaml::Null, Into::into)
+}
+
+fn millis(d: Duration) -> Yaml {
+    u64::try_from(d.as_millis()).unwrap_or(u64::MAX).into()
+}
+
+fn labeled_table<'a>(
+    prefix: &str,
+    modes: &'a HashMap<Profile, ModeSet>,
+    default: Option<&'a ModeSet>,
+) -> Vec<(String, &'a ModeSet)> {
+    let mut sets: Vec<_> = Profile::all()
+        .into_iter()
+        .filter_map(|p| Some((format!("{prefix}{}", p.as_config_key()), modes.get(&p)?)))
+        .collect();
+    if let Some(set) = default {
+        sets.push((format!("{prefix}{DEFAULT_MODE_KEY}"), set));
+    }
+    sets
+}
+
+/// Binary to run for `name`: the env override, else the config value, else
+/// the bare name (resolved through PATH at exec time).
+fn bin_path(env_var: &str, configured: Option<PathBuf>, name: &str) -> PathBuf {
+    env::var_os(env_var)
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or(configured)
+        .unwrap_or_else(|| PathBuf::from(name))
+}
+
+/// Run a selector through `/bin/sh -c` and return its trimmed stdout.
+async fn run_selector(cmd: &str, timeout: Duration) -> Result<String, String> {
+    debug!(cmd, "[selector] running");
+    let mut sh = std::process::Command::new("/bin/sh");
+    sh.arg("-c").arg(cmd).stdin(Stdio::null());
+    let out = rt::output_within(sh, timeout)
+        .await
+        .map_err(|rt::Elapsed| format!("timed out after {timeout:?}: {cmd}"))?
+        .map_err(|e| format!("failed to exec: {cmd}: {e}"))?;
+
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    if !stderr.trim().is_empty() {
+        warn!("[selector] {}", stderr.trim());
+    }
+    if !out.status.success() {
+        return Err(format!(
+            "exited with {}: {cmd}",
+            out.status.code().unwrap_or(-1)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_owned())
+}
+
+/// Load the config from `path_override`, or the first file found in
+/// `config_search_paths`, and validate it.
+pub fn load_config(path_override: Option<&Path>) -> Result<Config, ConfigError> {
+    let path = config_path(path_override)?;
+    let contents = read_config_file(&path)?;
+    let raw: RawConfig =
+        serde_yaml::from_str(&contents).map_err(|e| ConfigError::parse(&path, e))?;
+    build_config(raw, vec![path])
+}
+
+/// Layer every file in `config_search_paths` that exists, `/etc` first and
+/// `~/.config` last, and validate the result. A later file replaces single
+/// settings and whole entries under `modes`, each preset, `aliases` and
+/// `tuned_profiles`; it doesn't need to be a complete config by itself.
+/// Errors name the most specific file, which becomes `Config::path`.
+pub fn load_merged_config() -> Result<Config, ConfigError> {
+    use serde_yaml::Value as Yaml;
+
+    let candidates = config_search_paths();
+    let mut layers: Vec<PathBuf> = candidates.iter().filter(|p| p.exists()).cloned().collect();
+    if layers.is_empty() {
+        return Err(ConfigError::NotFound {
+            searched: candidates,
+        });
+    }
+    layers.reverse();
+
+    let mut merged = serde_yaml::Mapping::new();
+    for path in &layers {
+        let contents = read_config_file(path)?;
+        let layer: Yaml =
+            serde_yaml::from_str(&contents).map_err(|e| ConfigError::parse(path, e))?;
+        match layer {
+            Yaml::Null => {}
+            Yaml::Mapping(map) => merge_layer(&mut merged, map),
+            _ => {
+                return Err(ConfigError::invalid(
+                    path,
+                    format!("{} is not a YAML mapping", path.display()),
+                ));
+            }
+        }
+        debug!(config = %path.display(), "merged config layer");
+    }
+    let top = layers.last().expect("checked non-empty above");
+    let raw: RawConfig =
+        serde_yaml::from_value(Yaml::Mapping(merged)).map_err(|e| ConfigError::parse(top, e))?;
+    build_config(raw, layers)
+}
+
+/// Merge one file's top-level map into `merged`.
+fn merge_layer(merged: &mut serde_yaml::Mapping, layer: serde_yaml::Mapping) {
+    for (key, value) in layer {
+        // Levels below `key` merged entry by entry rather than replaced:
+        // modes.<profile>, aliases.<name>, presets.<name>.<profile>, ...
+        let depth = match key.as_str() {
+            Some("modes" | "aliases" | "tuned_profiles" | "performance_degraded") => 1,
+            Some("presets") => 2,
+            _ => 0,
+        };
+        match merged.get_mut(&key) {
+            Some(base) => merge_yaml(base, value, depth),
+            None => {
+                merged.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Put `layer` over `base`, merging maps key by key `depth` levels down;
+/// anything below that, and every other kind of value, is replaced.
+fn merge_yaml(base: &mut serde_yaml::Value, layer: serde_yaml::Value, depth: usize) {
+    use serde_yaml::Value as Yaml;
+
+    match (base, layer) {
+        (Yaml::Mapping(base), Yaml::Mapping(layer)) if depth > 0 => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(entry) => merge_yaml(entry, value, depth - 1),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
+
+/// Read `path` and reject a `version:` newer than this build.
+fn read_config_file(path: &Path) -> Result<String, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_owned(),
+        source,
+    })?;
+    let header: ConfigHeader =
+        serde_yaml::from_str(&contents).map_err(|e| ConfigError::parse(path, e))?;
+    check_version(header.version, path)?;
+    Ok(contents)
+}
+
+/// Turn a parsed file (or merged files, most specific last) into a `Config`.
+fn build_config(raw: RawConfig, layers: Vec<PathBuf>) -> Result<Config, ConfigError> {
+    let path = layers.last().expect("at least one config file").clone();
+    let sched = raw.sched.as_deref();
+    let Preset { modes, default } = load_mode_table(raw.modes, sched, "", &path)?;
+    let mut presets = HashMap::new();
+    for (name, defs) in raw.presets.0 {
+        let table = load_mode_table(defs, sched, &format!("{name}."), &path)?;
+        if presets.insert(name.clone(), table).is_some() {
+            return Err(ConfigError::invalid(
+                &path,
+                format!("duplicate preset '{name}' in {}", path.display()),
+            ));
+        }
+    }
+
+    let mut performance_degraded = HashMap::new();
+    for (reason, mut definition) in raw.performance_degraded.0 {
+        if let Some(sched) = sched {
+            definition.inherit_sched(sched);
+        }
+        let set = ModeSet::try_from(definition).map_err(|e| {
+            ConfigError::invalid(
+                &path,
+                format!(
+                    "performance_degraded entry '{reason}' in {}: {e}",
+                    path.display()
+                ),
+            )
+        })?;
+        if performance_degraded.insert(reason.clone(), set).is_some() {
+            return Err(ConfigError::invalid(
+                &path,
+                format!(
+                    "duplicate performance_degraded entry '{reason}' in {}",
+                    path.display()
+                ),
+            ));
+        }
+    }
+
+    let tuned_profiles = match raw.tuned_profiles {
+        Some(map) => map
+            .into_iter()
+            .map(|(name, key)| {
+                let profile = Profile::from_str(&key).map_err(|e| {
+                    ConfigError::invalid(
+                        &path,
+                        format!("tuned_profiles entry '{name}' in {}: {e}", path.display()),
+                    )
+                })?;
+                Ok((name, profile))
+            })
+            .collect::<Result<_, ConfigError>>()?,
+        None => default_tuned_profiles(),
+    };
+
+    let aliases = raw
+        .aliases
+        .into_iter()
+        .map(|(name, key)| {
+            if Profile::from_str(&name).is_ok() {
+                return Err(ConfigError::invalid(
+                    &path,
+                    format!(
+                        "aliases entry '{name}' in {} is already a profile name",
+                        path.display()
+                    ),
+                ));
+            }
+            let profile = Profile::from_str(&key).map_err(|e| {
+                ConfigError::invalid(
+                    &path,
+                    format!("aliases entry '{name}' in {}: {e}", path.display()),
+                )
+            })?;
+            Ok((name, profile))
+        })
+        .collect::<Result<_, ConfigError>>()?;
+
+    let state_file = raw
+        .state_file
+        .map(|file| expand_env(&file).map(PathBuf::from))
+        .transpose()
+        .map_err(|e| {
+            ConfigError::invalid(&path, format!("'state_file' in {}: {e}", path.display()))
+        })?;
+
+    let global_args = raw
+        .scxctl_global_args
+        .iter()
+        .map(|arg| expand_env(arg))
+        .collect::<Result<_, _>>()
+        .map_err(|e| {
+            ConfigError::invalid(
+                &path,
+                format!("'scxctl_global_args' in {}: {e}", path.display()),
+            )
+        })?;
+
+    let mut config = Config {
+        path,
+        layers,
+        modes,
+        default,
+        debounce: Duration::from_millis(raw.debounce_ms),
+        min_switch_interval: Duration::from_millis(raw.min_switch_interval_ms),
+        cooldown_after_failures: raw.cooldown_after_failures,
+        failure_cooldown: Duration::from_millis(raw.failure_cooldown_ms),
+        poll_interval: Duration::from_millis(raw.poll_interval_ms),
+        reapply_on_resume: raw.reapply_on_resume,
+        stop_on_exit: raw.stop_on_exit,
+        missing_sched_ext: raw.missing_sched_ext,
+        state_file,
+        shutdown_timeout: Duration::from_millis(raw.shutdown_timeout_ms),
+        startup_wait: Duration::from_millis(raw.startup_wait_ms),
+        startup_delay: Duration::from_millis(raw.startup_delay_ms),
+        history_size: raw.history_size,
+        scx: ScxSettings {
+            bin: bin_path(SCXCTL_ENV, raw.scxctl_path, "scxctl"),
+            global_args,
+            retries: raw.retries,
+            retry_delay: Duration::from_millis(raw.retry_delay_ms),
+            timeout: Duration::from_millis(raw.scxctl_timeout_ms),
+            verify: raw
+                .verify_switch
+                .then(|| Duration::from_millis(raw.verify_switch_timeout_ms)),
+            runner: Arc::new(ProcessRunner),
+            version: None,
+        },
+        powerprofilesctl: bin_path(
+            POWERPROFILESCTL_ENV,
+            raw.powerprofilesctl_path,
+            "powerprofilesctl",
+        ),
+        source: raw.source,
+        bus: raw.bus,
+        tuned_profiles,
+        aliases,
+        ppd_dbus: raw.ppd_dbus,
+        ignore_holds: raw.ignore_holds,
+        presets,
+        active_preset: None,
+        profile_map: HashMap::new(),
+        performance_degraded,
+    };
+    config.select_preset(raw.preset.as_deref()).map_err(|e| {
+        ConfigError::invalid(
+            &config.path,
+            format!("'preset' in {}: {e}", config.path.display()),
+        )
+    })?;
+
+    if raw.require_all_profiles {
+        for preset in std::iter::once(None).chain(config.preset_names().into_iter().map(Some)) {
+            for profile in Profile::all() {
+                if config.mode_set_in(profile, preset).is_none() {
+                    return Err(ConfigError::MissingProfile {
+                        path: config.path.clone(),
+                        preset: preset.map(str::to_owned),
+                        profile,
+                    });
+                }
+            }
+        }
+    }
+
+    info!(config = %config.path.display(), "loaded configuration");
+    Ok(config)
+}
+
+/// Parse one `modes`-shaped map, filling in the top-level `sched` where a
+/// mode has none. `prefix` is prepended to keys in errors.
+fn load_mode_table(
+    defs: Entries<ProfileDefinition>,
+    sched: Option<&str>,
+    prefix: &str,
+    path: &Path,
+) -> Result<Preset, ConfigError> {
+    let mut table = Preset::default();
+    for (key, mut definition) in defs.0 {
+        if let Some(sched) = sched {
+            definition.inherit_sched(sched);
+        }
+        let set = ModeSet::try_from(definition).map_err(|e| {
+            ConfigError::invalid(
+                path,
+                format!("mode '{prefix}{key}' in {}: {e}", path.display()),
+            )
+        })?;
+        for problem in set.modes().iter().flat_map(|mode| mode.suspicious()) {
+            warn!("mode '{prefix}{key}' in {}: {problem}", path.display());
+        }
+        if key == DEFAULT_MODE_KEY {
+            if table.default.replace(set).is_some() {
+                return Err(ConfigError::invalid(
+                    path,
+                    format!(
+                        "duplicate '{prefix}{DEFAULT_MODE_KEY}' mode in {}",
+                        path.display()
+                    ),
+                ));
+            }
+            continue;
+        }
+        let profile = Profile::from_str(key.as_str()).map_err(|e| {
+            ConfigError::invalid(
+                path,
+                format!("unknown profile '{prefix}{key}' in {}: {e}", path.display()),
+            )
+        })?;
+        if table.modes.insert(profile, set).is_some() {
+            return Err(ConfigError::invalid(
+                path,
+                format!(
+                    "duplicate configuration for profile '{prefix}{}' in {}",
+                    profile.as_config_key(),
+                    path.display()
+                ),
+            ));
+        }
+    }
+    Ok(table)
+}
+
+/// `path_override` if it exists, else the first existing search path.
+pub fn config_path(path_override: Option<&Path>) -> Result<PathBuf, ConfigError> {
+    match path_override {
+        Some(path) => {
+            if !path.exists() {
+                return Err(ConfigError::MissingFile(path.to_owned()));
+            }
+            debug!(config = %path.display(), "using explicit config path; search skipped");
+            Ok(path.to_path_buf())
+        }
+        None => ensure_config_file(),
+    }
+}
+
+/// Rewrite a config to the current schema: every string `args` becomes a
+/// token list and `version` is set to `CONFIG_VERSION`. Works on the YAML
+/// tree, so keys this build doesn't know survive (comments don't). Running
+/// it on its own output changes nothing. `path` is only for errors.
+pub fn migrate_config(contents: &str, path: &Path) -> Result<String, ConfigError> {
+    fn migrate_mode(mode: &mut Yaml) {
+        let Some(args) = mode.get_mut("args") else {
+            return;
+        };
+        let Some(joined) = args.as_str() else {
+            return;
+        };
+        // `${VAR:-a b}` would be torn apart by splitting; leave such strings.
+        let splittable = joined.split("${").skip(1).all(|reference| {
+            !reference
+                .split('}')
+                .next()
+                .unwrap_or("")
+                .contains(char::is_whitespace)
+        });
+        if splittable {
+            *args = Yaml::Sequence(
+                joined
+                    .split_whitespace()
+                    .map(|t| Yaml::String(t.to_owned()))
+                    .collect(),
+            );
+        } else {
+            warn!(
+                "[migrate] left args {joined:?} as a string: a ${{...}} reference contains whitespace"
+            );
+        }
+    }
+
+    fn migrate_table(table: &mut Yaml) {
+        let Some(entries) = table.as_mapping_mut() else {
+            return;
+        };
+        for (_, entry) in entries.iter_mut() {
+            let split = entry
+                .as_mapping()
+                .is_some_and(|m| m.contains_key("ac") || m.contains_key("battery"));
+            if split {
+                for side in ["ac", "battery"] {
+                    if let Some(mode) = entry.get_mut(side) {
+                        migrate_mode(mode);
+                    }
+                }
+            } else if let Some(choices) = entry.get_mut("choices").and_then(Yaml::as_mapping_mut) {
+                for (_, mode) in choices.iter_mut() {
+                    migrate_mode(mode);
+                }
+            } else {
+                migrate_mode(entry);
+            }
+        }
+    }
+
+    let mut root: Yaml = serde_yaml::from_str(contents).map_err(|e| ConfigError::parse(path, e))?;
+    let Some(top) = root.as_mapping_mut() else {
+        return Err(ConfigError::invalid(path, "config is not a YAML mapping"));
+    };
+    let version = top.get("version").and_then(Yaml::as_u64);
+    if version.is_some_and(|v| v > u64::from(CONFIG_VERSION)) {
+        return Err(ConfigError::invalid(
+            path,
+            format!(
+                "config version {} is newer than this build understands ({CONFIG_VERSION}); upgrade scx-power-sync-dbus",
+                version.unwrap_or_default()
+            ),
+        ));
+    }
+
+    for key in ["modes", "performance_degraded"] {
+        if let Some(table) = top.get_mut(key) {
+            migrate_table(table);
+        }
+    }
+    if let Some(presets) = top.get_mut("presets").and_then(Yaml::as_mapping_mut) {
+        for (_, table) in presets.iter_mut() {
+            migrate_table(table);
+        }
+    }
+
+    // Put `version` first, where a reader looks for it.
+    let mut stamped = Mapping::new();
+    stamped.insert("version".into(), Yaml::Number(CONFIG_VERSION.into()));
+    for (key, value) in std::mem::take(top) {
+        if key.as_str() != Some("version") {
+            stamped.insert(key, value);
+        }
+    }
+    Ok(serde_yaml::to_string(&Yaml::Mapping(stamped)).expect("a YAML tree always serializes"))
+}
+
+fn ensure_config_file() -> Result<PathBuf, ConfigError> {
+    let candidates = config_search_paths();
+    for candidate in &candidates {
+        if candidate.exists() {
+            debug!(candidate = %candidate.display(), "config candidate found; using it");
+            return Ok(candidate.clone());
+        }
+        debug!(candidate = %candidate.display(), "config candidate missing");
+    }
+    Err(ConfigError::NotFound {
+        searched: candidates,
+    })
+}
+
+/// Candidate config locations, highest priority first. Never empty: the
+/// `/etc` fallback is always listed.
+pub fn config_search_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    // Under a service manager with a cleared environment these are often all
+    // missing, which is why a config in the home directory isn't found.
+    let mut unset = Vec::new();
+
+    match env::var("HOME") {
+        Ok(home) if !home.is_empty() => {
+            let home_config = Path::new(&home)
+                .join(".config")
+                .join(CONFIG_DIR_NAME)
+                .join(CONFIG_FILE_NAME);
+            paths.push(home_config);
+        }
+        _ => unset.push("HOME"),
+    }
+
+    if let Ok(value) = env::var("XDG_CONFIG_HOME")
+        && !value.is_empty()
+    {
+        let xdg_path = PathBuf::from(&value)
+            .join(CONFIG_DIR_NAME)
+            .join(CONFIG_FILE_NAME);
+        if !paths.contains(&xdg_path) {
+            paths.push(xdg_path);
+        }
+    } else {
+        unset.push("XDG_CONFIG_HOME");
+    }
+
+    // Per the XDG spec, a value with no non-empty entries counts as unset.
+    let dirs = env::var("XDG_CONFIG_DIRS").unwrap_or_default();
+    let mut dirs = dirs.split(':').filter(|s| !s.is_empty()).peekable();
+    if dirs.peek().is_some() {
+        for entry in dirs {
+            let path = PathBuf::from(entry)
+                .join(CONFIG_DIR_NAME)
+                .join(CONFIG_FILE_NAME);
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    } else {
+        unset.push("XDG_CONFIG_DIRS (/etc/xdg searched instead)");
+        let path = PathBuf::from("/etc/xdg")
+            .join(CONFIG_DIR_NAME)
+            .join(CONFIG_FILE_NAME);
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+
+    let fallback = PathBuf::from("/etc")
+        .join(CONFIG_DIR_NAME)
+        .join(CONFIG_FILE_NAME);
+    if !paths.contains(&fallback) {
+        paths.push(fallback);
+    }
+
+    if !unset.is_empty() {
+        debug!(
+            unset = %unset.join(", "),
+            "config search: environment variables unset or empty, so their locations are not searched"
+        );
+    }
+    paths
+}
@@ -0,0 +1,1013 @@
+//! The D-Bus side of the daemon: the profile sources (power-profiles-daemon,
+//! TuneD), UPower, logind and the `org.scx.PowerSync` status interface.
+
+use crate::history::History;
+use crate::ping_watchdog;
+use anyhow::anyhow;
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use futures_util::future::BoxFuture;
+use scx_power_sync_dbus::{
+    BusKind, Config, Mode, PPD_CANDIDATES, PowerSource, PpdBus, Profile, SourceKind,
+    decode_profile_name,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+use zbus::fdo::{PropertiesChanged, PropertiesChangedStream, PropertiesProxy};
+use zbus::object_server::SignalEmitter;
+use zbus::proxy::SignalStream;
+use zbus::{Connection, Proxy};
+use zvariant::{OwnedValue, Value};
+
+const TUNED_DEST: &str = "com.redhat.tuned";
+const TUNED_PATH: &str = "/Tuned";
+const TUNED_IFACE: &str = "com.redhat.tuned.control";
+const UPOWER_DEST: &str = "org.freedesktop.UPower";
+const UPOWER_PATH: &str = "/org/freedesktop/UPower";
+const UPOWER_IFACE: &str = "org.freedesktop.UPower";
+const LOGIND_DEST: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const LOGIND_IFACE: &str = "org.freedesktop.login1.Manager";
+pub const STATUS_NAME: &str = "org.scx.PowerSync";
+pub const STATUS_PATH: &str = "/org/scx/PowerSync";
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+/// Startup `ActiveProfile` reads while the source is still coming up: up to
+/// this many, 250 ms apart at first and doubling (about 8 s in all).
+const STARTUP_READ_ATTEMPTS: u32 = 6;
+const STARTUP_READ_INITIAL_DELAY: Duration = Duration::from_millis(250);
+/// D-Bus errors meaning the profile source hasn't finished starting: its name
+/// isn't owned yet, or its object isn't exported or answering yet.
+const NOT_READY_ERRORS: [&str; 8] = [
+    "org.freedesktop.DBus.Error.ServiceUnknown",
+    "org.freedesktop.DBus.Error.NameHasNoOwner",
+    "org.freedesktop.DBus.Error.NoReply",
+    "org.freedesktop.DBus.Error.Timeout",
+    "org.freedesktop.DBus.Error.UnknownObject",
+    "org.freedesktop.DBus.Error.UnknownInterface",
+    "org.freedesktop.DBus.Error.UnknownProperty",
+    "org.freedesktop.DBus.Error.UnknownMethod",
+];
+
+/// What the D-Bus methods ask of the event loop.
+pub enum Request {
+    Reload,
+    /// `None` goes back to the top-level `modes`.
+    SetPreset(Option<String>),
+}
+
+/// `org.scx.PowerSync`: read-only view of what the daemon last applied, plus
+/// `ReloadConfig` and `SetPreset`.
+struct StatusInterface {
+    profile: String,
+    sched: String,
+    args: Vec<String>,
+    description: String,
+    preset: String,
+    presets: Vec<String>,
+    requests: mpsc::UnboundedSender<Request>,
+    history: Arc<History>,
+}
+
+impl StatusInterface {
+    fn request(&self, request: Request) -> zbus::fdo::Result<()> {
+        self.requests
+            .send(request)
+            .map_err(|_| zbus::fdo::Error::Failed("daemon is shutting down".into()))
+    }
+}
+
+#[zbus::interface(name = "org.scx.PowerSync")]
+impl StatusInterface {
+    /// Config key of the profile in effect, or "" if none.
+    #[zbus(property)]
+    fn current_profile(&self) -> String {
+        self.profile.clone()
+    }
+
+    /// Scheduler of the mode in effect, or "" if none.
+    #[zbus(property)]
+    fn current_sched(&self) -> String {
+        self.sched.clone()
+    }
+
+    #[zbus(property)]
+    fn current_args(&self) -> Vec<String> {
+        self.args.clone()
+    }
+
+    /// The mode's `description` from the config, or "" if it has none.
+    #[zbus(property)]
+    fn current_description(&self) -> String {
+        self.description.clone()
+    }
+
+    /// Active preset, or "" when resolving through `modes` only.
+    #[zbus(property)]
+    fn current_preset(&self) -> String {
+        self.preset.clone()
+    }
+
+    /// Names accepted by `SetPreset`.
+    #[zbus(property)]
+    fn presets(&self) -> Vec<String> {
+        self.presets.clone()
+    }
+
+    /// Recent applies, oldest first: (timestamp, profile, sched, ok, error).
+    fn history(&self) -> Vec<(u64, String, String, bool, String)> {
+        self.history.entries()
+    }
+
+    /// Same as sending SIGHUP. Returns once the reload is queued.
+    fn reload_config(&self) -> zbus::fdo::Result<()> {
+        self.request(Request::Reload)
+    }
+
+    /// Select a preset ("" for none) and re-apply the current profile.
+    fn set_preset(&self, name: String) -> zbus::fdo::Result<()> {
+        if !name.is_empty() && !self.presets.contains(&name) {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "unknown preset '{name}'"
+            )));
+        }
+        self.request(Request::SetPreset(Some(name).filter(|n| !n.is_empty())))
+    }
+
+    #[zbus(signal)]
+    async fn profile_changed(
+        emitter: &SignalEmitter<'_>,
+        profile: &str,
+        sched: &str,
+    ) -> zbus::Result<()>;
+}
+
+/// Live D-Bus handles; rebuilt from scratch whenever the bus connection drops.
+pub struct Session {
+    conn: Connection,
+    pub source: Box<dyn ProfileSource>,
+    /// Only present when the config has ac/battery variants and UPower answered.
+    pub upower: Option<PropertiesChangedStream>,
+    pub power: PowerSource,
+    /// Where PPD was found; `None` for TuneD.
+    pub ppd_bus: Option<PpdBus>,
+    /// PPD's properties, only watched when the config has `performance_degraded`.
+    pub ppd_changes: Option<PropertiesChangedStream>,
+    /// logind's `PrepareForSleep`, only watched with `reapply_on_resume`.
+    pub logind: Option<SignalStream<'static>>,
+    /// `PerformanceDegraded` as PPD last reported it; "" when not degraded.
+    pub degraded: String,
+}
+
+impl Session {
+    /// A session over `conn` that watches nothing but `source`, for driving
+    /// the daemon in tests.
+    #[cfg(test)]
+    pub fn detached(conn: Connection, source: Box<dyn ProfileSource>) -> Self {
+        Session {
+            conn,
+            source,
+            upower: None,
+            power: PowerSource::Ac,
+            ppd_bus: None,
+            ppd_changes: None,
+            logind: None,
+            degraded: String::new(),
+        }
+    }
+
+    /// `CurrentProfile` and `CurrentSched` as last published.
+    #[cfg(test)]
+    pub async fn published(&self) -> (String, String) {
+        let iface = self
+            .conn
+            .object_server()
+            .interface::<_, StatusInterface>(STATUS_PATH)
+            .await
+            .expect("status interface is served");
+        let status = iface.get().await;
+        (status.profile.clone(), status.sched.clone())
+    }
+
+    /// Export `org.scx.PowerSync` on this connection. Not fatal: the system
+    /// bus needs a policy file before an unprivileged process may own a name.
+    pub async fn serve_status(
+        &self,
+        requests: &mpsc::UnboundedSender<Request>,
+        history: &Arc<History>,
+    ) {
+        let status = StatusInterface {
+            profile: String::new(),
+            sched: String::new(),
+            args: Vec::new(),
+            description: String::new(),
+            preset: String::new(),
+            presets: Vec::new(),
+            requests: requests.clone(),
+            history: history.clone(),
+        };
+        let served = async {
+            self.conn.object_server().at(STATUS_PATH, status).await?;
+            self.conn.request_name(STATUS_NAME).await
+        };
+        match served.await {
+            Ok(()) => info!(name = STATUS_NAME, "[status] serving D-Bus interface"),
+            Err(e) => warn!("[status] could not publish {STATUS_NAME}: {e}"),
+        }
+    }
+
+    /// Give up `org.scx.PowerSync` so no further method calls reach us.
+    pub async fn stop_serving(&self) {
+        if let Err(e) = self.conn.release_name(STATUS_NAME).await {
+            debug!("[status] release name failed: {e}");
+        }
+        if let Err(e) = self
+            .conn
+            .object_server()
+            .remove::<StatusInterface, _>(STATUS_PATH)
+            .await
+        {
+            debug!("[status] remove interface failed: {e}");
+        }
+    }
+
+    /// Update `org.scx.PowerSync` properties and emit `ProfileChanged` when
+    /// anything differs from what was published before.
+    pub async fn publish_status(
+        &self,
+        profile: Option<Profile>,
+        mode: Option<&Mode>,
+        config: &Config,
+    ) {
+        let Ok(iface) = self
+            .conn
+            .object_server()
+            .interface::<_, StatusInterface>(STATUS_PATH)
+            .await
+        else {
+            return;
+        };
+
+        let profile = profile.map_or("", Profile::as_config_key);
+        let sched = mode.map_or("", |m| m.sched.as_str());
+        let args = mode.map(|m| m.args.clone()).unwrap_or_default();
+        let description = mode.and_then(|m| m.description.as_deref()).unwrap_or("");
+        let preset = config.active_preset.as_deref().unwrap_or("");
+        let presets: Vec<String> = config.preset_names().into_iter().map(Into::into).collect();
+
+        let mut status = iface.get_mut().await;
+        let profile_changed = status.profile != profile;
+        let sched_changed = status.sched != sched;
+        let args_changed = status.args != args;
+        let description_changed = status.description != description;
+        let preset_changed = status.preset != preset;
+        let presets_changed = status.presets != presets;
+        status.profile = profile.to_owned();
+        status.sched = sched.to_owned();
+        status.args = args;
+        status.description = description.to_owned();
+        status.preset = preset.to_owned();
+        status.presets = presets;
+
+        let emitter = iface.signal_emitter();
+        let emitted = async {
+            if profile_changed {
+                status.current_profile_changed(emitter).await?;
+            }
+            if sched_changed {
+                status.current_sched_changed(emitter).await?;
+            }
+            if args_changed {
+                status.current_args_changed(emitter).await?;
+            }
+            if description_changed {
+                status.current_description_changed(emitter).await?;
+            }
+            if preset_changed {
+                status.current_preset_changed(emitter).await?;
+            }
+            if presets_changed {
+                status.presets_changed(emitter).await?;
+            }
+            if profile_changed || sched_changed || args_changed {
+                StatusInterface::profile_changed(emitter, profile, sched).await?;
+            }
+            zbus::Result::Ok(())
+        };
+        if let Err(e) = emitted.await {
+            debug!("[status] emit failed: {e}");
+        }
+    }
+
+    /// Connect to the configured profile source, first waiting up to `wait`
+    /// for it to show up on the bus.
+    pub async fn connect(config: &Config, wait: Duration) -> Result<Self> {
+        // PPD and TuneD both live on the system bus, unless `bus` says otherwise.
+        let conn = match config.bus {
+            BusKind::System => Connection::system().await,
+            BusKind::Session => Connection::session().await,
+        }
+        .with_context(|| format!("connect {} D-Bus", config.bus.as_str()))?;
+
+        if !wait.is_zero() {
+            let names: Vec<&str> = match (config.source, &config.ppd_dbus) {
+                (SourceKind::Tuned, _) => vec![TUNED_DEST],
+                (SourceKind::Ppd, Some(bus)) => vec![bus.name.as_str()],
+                (SourceKind::Ppd, None) => PPD_CANDIDATES.iter().map(|c| c.0).collect(),
+            };
+            if let Err(e) = wait_for_name(&conn, &names, wait).await {
+                warn!("[startup] could not wait for {}: {e:#}", names.join(" / "));
+            }
+        }
+
+        let mut ppd_bus = None;
+        let source: Box<dyn ProfileSource> = match config.source {
+            SourceKind::Ppd => {
+                let bus = match &config.ppd_dbus {
+                    Some(bus) => {
+                        info!(name = %bus.name, "[ppd] using configured D-Bus name");
+                        bus.clone()
+                    }
+                    None => detect_ppd_bus(&conn).await,
+                };
+                ppd_bus = Some(bus.clone());
+                Box::new(PpdSource::connect(&conn, bus, config.ignore_holds).await?)
+            }
+            SourceKind::Tuned => {
+                if config.ignore_holds {
+                    warn!("[holds] ignore_holds needs source: ppd; ignoring it");
+                }
+                Box::new(TunedSource::connect(&conn).await?)
+            }
+        };
+
+        let mut session = Self {
+            conn,
+            source,
+            upower: None,
+            power: PowerSource::default(),
+            ppd_bus,
+            ppd_changes: None,
+            logind: None,
+            degraded: String::new(),
+        };
+        if config.watches_power_source() {
+            session.watch_power_source().await;
+        }
+        if !config.performance_degraded.is_empty() {
+            session.watch_degraded().await;
+        }
+        if config.reapply_on_resume {
+            session.watch_sleep(config.bus).await;
+        }
+        Ok(session)
+    }
+
+    /// Subscribe to PPD's `PerformanceDegraded`. Older daemons don't have
+    /// it: on failure we log and treat performance as never degraded.
+    pub async fn watch_degraded(&mut self) {
+        let Some(bus) = &self.ppd_bus else {
+            warn!("[degraded] performance_degraded needs source: ppd; ignoring it");
+            return;
+        };
+        match degraded_subscribe(&self.conn, bus).await {
+            Ok((stream, degraded)) => {
+                info!(degraded = %degraded, "[degraded] watching PerformanceDegraded");
+                self.ppd_changes = Some(stream);
+                self.degraded = degraded;
+            }
+            Err(e) => warn!("[degraded] unavailable, ignoring performance_degraded: {e:#}"),
+        }
+    }
+
+    /// Subscribe to UPower's `OnBattery`. UPower is optional: on failure we
+    /// log and keep assuming AC.
+    pub async fn watch_power_source(&mut self) {
+        match upower_subscribe(&self.conn).await {
+            Ok((stream, power)) => {
+                info!(?power, "[upower] watching power source");
+                self.upower = Some(stream);
+                self.power = power;
+            }
+            Err(e) => warn!("[upower] unavailable, assuming AC power: {e:#}"),
+        }
+    }
+
+    /// Subscribe to logind's `PrepareForSleep`. logind is always on the
+    /// system bus, so with `bus: session` that is connected to separately.
+    /// On failure we log and never see a resume.
+    pub async fn watch_sleep(&mut self, bus: BusKind) {
+        let conn = match bus {
+            BusKind::System => Ok(self.conn.clone()),
+            BusKind::Session => Connection::system().await.context("connect system D-Bus"),
+        };
+        match async { sleep_subscribe(&conn?).await }.await {
+            Ok(stream) => {
+                info!("[resume] watching logind for resume from suspend");
+                self.logind = Some(stream);
+            }
+            Err(e) => warn!("[resume] logind unavailable, not re-applying on resume: {e:#}"),
+        }
+    }
+
+    /// Retry `connect` with exponential backoff until it succeeds.
+    pub async fn reconnect(config: &Config) -> Self {
+        let mut delay = RECONNECT_INITIAL_DELAY;
+        let mut attempt = 0u32;
+        loop {
+            attempt += 1;
+            // Waiting on the bus is expected, not a hang; keep systemd from
+            // killing us while we back off.
+            ping_watchdog();
+            tokio::time::sleep(delay).await;
+            warn!(
+                attempt,
+                "[reconnect] connecting to {} D-Bus",
+                config.bus.as_str()
+            );
+            match Self::connect(config, Duration::ZERO).await {
+                Ok(session) => {
+                    info!(attempt, "[reconnect] connected");
+                    return session;
+                }
+                Err(e) => warn!(attempt, "[reconnect] {e:#}; retrying in {delay:?}"),
+            }
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+    }
+}
+
+async fn upower_subscribe(conn: &Connection) -> Result<(PropertiesChangedStream, PowerSource)> {
+    let upower = Proxy::new(conn, UPOWER_DEST, UPOWER_PATH, UPOWER_IFACE)
+        .await
+        .context("create UPower proxy")?;
+    let on_battery: bool = upower
+        .get_property("OnBattery")
+        .await
+        .context("read OnBattery")?;
+    let props = PropertiesProxy::new(conn, UPOWER_DEST, UPOWER_PATH)
+        .await
+        .context("create UPower Properties proxy")?;
+    let stream = props
+        .receive_properties_changed()
+        .await
+        .context("subscribe UPower PropertiesChanged")?;
+    Ok((stream, PowerSource::from_on_battery(on_battery)))
+}
+
+/// Resolves with the new power source when `OnBattery` changes; never
+/// resolves while UPower isn't watched. If the UPower stream ends we stop
+/// watching (the main PPD stream ending is what triggers a full reconnect).
+pub async fn next_power_change(
+    upower: &mut Option<PropertiesChangedStream>,
+) -> Option<PowerSource> {
+    let Some(stream) = upower else {
+        return std::future::pending().await;
+    };
+    while let Some(signal) = stream.next().await {
+        let Ok(args) = signal.args() else { continue };
+        if !should_handle(args.interface_name(), UPOWER_IFACE) {
+            continue;
+        }
+        if let Some(Value::Bool(on_battery)) = args.changed_properties().get("OnBattery") {
+            return Some(PowerSource::from_on_battery(*on_battery));
+        }
+    }
+    warn!("[upower] PropertiesChanged stream ended; no longer watching power source");
+    *upower = None;
+    None
+}
+
+async fn sleep_subscribe(conn: &Connection) -> Result<SignalStream<'static>> {
+    let logind: Proxy<'static> = zbus::proxy::Builder::new(conn)
+        .destination(LOGIND_DEST)
+        .and_then(|b| b.path(LOGIND_PATH))
+        .and_then(|b| b.interface(LOGIND_IFACE))
+        .context("create logind proxy")?
+        .cache_properties(zbus::proxy::CacheProperties::No)
+        .build()
+        .await
+        .context("create logind proxy")?;
+    logind
+        .receive_signal("PrepareForSleep")
+        .await
+        .context("subscribe logind PrepareForSleep")
+}
+
+/// Resolves with `PrepareForSleep`'s argument: true when suspending, false
+/// on resume. Never resolves while logind isn't watched; if its stream ends
+/// we stop watching.
+pub async fn next_sleep_change(logind: &mut Option<SignalStream<'static>>) -> Option<bool> {
+    let Some(stream) = logind else {
+        return std::future::pending().await;
+    };
+    while let Some(msg) = stream.next().await {
+        match msg.body().deserialize::<bool>() {
+            Ok(start) => return Some(start),
+            Err(e) => warn!("[resume] PrepareForSleep decode failed: {e}"),
+        }
+    }
+    warn!("[resume] logind signal stream ended; no longer watching for resume");
+    *logind = None;
+    None
+}
+
+async fn degraded_subscribe(
+    conn: &Connection,
+    bus: &PpdBus,
+) -> Result<(PropertiesChangedStream, String)> {
+    let props = PropertiesProxy::new(conn, bus.name.clone(), bus.path.clone())
+        .await
+        .context("create PPD Properties proxy")?;
+    let interface =
+        zbus::names::InterfaceName::try_from(bus.interface.as_str()).context("interface name")?;
+    let value = props
+        .get(interface, "PerformanceDegraded")
+        .await
+        .context("read PerformanceDegraded")?;
+    let degraded = decode_profile_name(&value).context("decode PerformanceDegraded")?;
+    let stream = props
+        .receive_properties_changed()
+        .await
+        .context("subscribe PPD PropertiesChanged")?;
+    Ok((stream, degraded))
+}
+
+/// Resolves with the new `PerformanceDegraded` value when PPD changes it on
+/// `interface`; never resolves when not watching.
+pub async fn next_degraded_change(
+    ppd: &mut Option<PropertiesChangedStream>,
+    interface: &str,
+) -> Option<String> {
+    let Some(stream) = ppd else {
+        return std::future::pending().await;
+    };
+    while let Some(signal) = stream.next().await {
+        let Ok(args) = signal.args() else { continue };
+        if !should_handle(args.interface_name(), interface) {
+            continue;
+        }
+        if let Some(value) = args.changed_properties().get("PerformanceDegraded") {
+            match decode_profile_name(value) {
+                Ok(degraded) => return Some(degraded),
+                Err(e) => warn!("PerformanceDegraded in PropertiesChanged: {e:#}"),
+            }
+        }
+    }
+    warn!("[degraded] PropertiesChanged stream ended; no longer watching PerformanceDegraded");
+    *ppd = None;
+    None
+}
+
+/// Whether a PropertiesChanged signal for `iface` is about the interface we
+/// watch. Exact: the legacy and current PPD names export the same properties
+/// and must not be mixed up.
+fn should_handle(iface: &str, expected: &str) -> bool {
+    iface == expected
+}
+
+/// Where the active power profile comes from. Names are reported as the
+/// source spells them; `Config::resolve_profile` maps them to a `Profile`.
+pub trait ProfileSource: Send + Sync {
+    /// The profile active right now.
+    fn active_profile(&self) -> BoxFuture<'_, Result<String>>;
+
+    /// Every profile the source offers on this machine.
+    fn available_profiles(&self) -> BoxFuture<'_, Result<Vec<String>>>;
+
+    /// The next profile the source switches to; `None` once its signal
+    /// stream has ended (which triggers a reconnect).
+    fn next_change(&mut self) -> BoxFuture<'_, Option<String>>;
+}
+
+/// power-profiles-daemon: `ActiveProfile` property and its PropertiesChanged.
+struct PpdSource {
+    bus: PpdBus,
+    proxy: Proxy<'static>,
+    stream: PropertiesChangedStream,
+    /// `ignore_holds`: report the profile underneath any hold.
+    ignore_holds: bool,
+    /// The last `ActiveProfile` seen with no hold active. PPD doesn't say
+    /// what a hold replaced, so this is all we know of it.
+    unheld: Mutex<Option<String>>,
+}
+
+/// One entry of PPD's `ActiveProfileHolds`.
+#[derive(Debug)]
+struct ProfileHold {
+    profile: String,
+    application_id: String,
+    reason: String,
+}
+
+impl PpdSource {
+    async fn connect(conn: &Connection, bus: PpdBus, ignore_holds: bool) -> Result<Self> {
+        // Uncached: a cache that failed to fill (PPD not up yet) keeps
+        // returning that error, so a retried read would never succeed.
+        let proxy = zbus::proxy::Builder::new(conn)
+            .destination(bus.name.clone())
+            .and_then(|b| b.path(bus.path.clone()))
+            .and_then(|b| b.interface(bus.interface.clone()))
+            .context("create PPD proxy")?
+            .cache_properties(zbus::proxy::CacheProperties::No)
+            .build()
+            .await
+            .context("create PPD proxy")?;
+
+        // Subscribe to property changes (ActiveProfile flips).
+        let props = PropertiesProxy::new(conn, bus.name.clone(), bus.path.clone())
+            .await
+            .context("create Properties proxy")?;
+        let stream = props
+            .receive_properties_changed()
+            .await
+            .context("subscribe PropertiesChanged")?;
+        Ok(Self {
+            bus,
+            proxy,
+            stream,
+            ignore_holds,
+            unheld: Mutex::new(None),
+        })
+    }
+
+    /// Profiles applications hold right now (`ActiveProfileHolds`, aa{sv}).
+    async fn holds(&self) -> Result<Vec<ProfileHold>> {
+        let holds: Vec<HashMap<String, OwnedValue>> = self
+            .proxy
+            .get_property("ActiveProfileHolds")
+            .await
+            .context("read ActiveProfileHolds")?;
+        let field = |hold: &HashMap<String, OwnedValue>, key| {
+            hold.get(key)
+                .and_then(|v| decode_profile_name(v).ok())
+                .unwrap_or_default()
+        };
+        Ok(holds
+            .iter()
+            .map(|hold| ProfileHold {
+                profile: field(hold, "Profile"),
+                application_id: field(hold, "ApplicationId"),
+                reason: field(hold, "Reason"),
+            })
+            .collect())
+    }
+
+    /// `active`, or with `ignore_holds` and a hold in place, the profile
+    /// that was active before it. Holds are read fresh rather than tracked
+    /// from signals, as PPD may report them before or after the profile.
+    async fn without_holds(&self, active: String) -> String {
+        if !self.ignore_holds {
+            return active;
+        }
+        let holds = match self.holds().await {
+            Ok(holds) => holds,
+            Err(e) => {
+                warn!("[holds] {e:#}; following ActiveProfile as is");
+                return active;
+            }
+        };
+        let mut unheld = self.unheld.lock().unwrap();
+        if holds.is_empty() {
+            *unheld = Some(active.clone());
+            return active;
+        }
+        for hold in &holds {
+            info!(
+                profile = %hold.profile,
+                application = %hold.application_id,
+                reason = %hold.reason,
+                "[holds] profile held"
+            );
+        }
+        match &*unheld {
+            Some(profile) => {
+                info!(held = %active, "[holds] ignoring the hold; staying with {profile}");
+                profile.clone()
+            }
+            None => {
+                warn!(
+                    held = %active,
+                    "[holds] the hold predates us, so the profile under it is unknown; following ActiveProfile until it's released"
+                );
+                active
+            }
+        }
+    }
+}
+
+/// Poll, with backoff, until one of `names` has an owner on the bus, giving up
+/// quietly after `limit`. Activatable names don't need waiting for: the first
+/// call starts the service.
+async fn wait_for_name(conn: &Connection, names: &[&str], limit: Duration) -> Result<()> {
+    let dbus = zbus::fdo::DBusProxy::new(conn)
+        .await
+        .context("create DBus proxy")?;
+    let activatable = dbus.list_activatable_names().await.unwrap_or_default();
+    if activatable.iter().any(|a| names.contains(&a.as_str())) {
+        return Ok(());
+    }
+
+    let deadline = Instant::now() + limit;
+    let mut delay = Duration::from_millis(100);
+    let mut waited = false;
+    loop {
+        for name in names {
+            let bus_name = zbus::names::BusName::try_from(*name).context("bus name")?;
+            if dbus
+                .name_has_owner(bus_name)
+                .await
+                .context("NameHasOwner")?
+            {
+                if waited {
+                    info!(name, "[startup] appeared on the bus");
+                }
+                return Ok(());
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            warn!(
+                "[startup] {} did not appear within {limit:?}; trying anyway",
+                names.join(" / ")
+            );
+            return Ok(());
+        }
+        if !waited {
+            info!(
+                "[startup] waiting up to {limit:?} for {}",
+                names.join(" / ")
+            );
+            waited = true;
+        }
+        ping_watchdog();
+        tokio::time::sleep(delay.min(deadline - now)).await;
+        delay = (delay * 2).min(Duration::from_secs(2));
+    }
+}
+
+/// The first of `PPD_CANDIDATES` that answers an `ActiveProfile` read. A
+/// name can be owned without serving the interface we expect (or not be
+/// running yet but activatable), so asking for the property is the only
+/// reliable check. Falls back to the legacy name, so a missing daemon fails
+/// the same way it always has.
+async fn detect_ppd_bus(conn: &Connection) -> PpdBus {
+    let to_bus = |(name, path, interface): (&str, &str, &str)| PpdBus {
+        name: name.to_owned(),
+        path: path.to_owned(),
+        interface: interface.to_owned(),
+    };
+    for candidate in PPD_CANDIDATES {
+        let bus = to_bus(candidate);
+        match probe_ppd_bus(conn, &bus).await {
+            Ok(()) => {
+                info!(name = %bus.name, "[ppd] detected D-Bus name");
+                return bus;
+            }
+            Err(e) => debug!(name = %bus.name, "[ppd] probe failed: {e:#}"),
+        }
+    }
+    warn!("[ppd] no power-profiles-daemon answered; trying the legacy name");
+    to_bus(PPD_CANDIDATES[PPD_CANDIDATES.len() - 1])
+}
+
+async fn probe_ppd_bus(conn: &Connection, bus: &PpdBus) -> Result<()> {
+    let props = PropertiesProxy::new(conn, bus.name.clone(), bus.path.clone())
+        .await
+        .context("create Properties proxy")?;
+    let interface =
+        zbus::names::InterfaceName::try_from(bus.interface.as_str()).context("interface name")?;
+    props
+        .get(interface, "ActiveProfile")
+        .await
+        .context("read ActiveProfile")?;
+    Ok(())
+}
+
+impl ProfileSource for PpdSource {
+    fn active_profile(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(async move {
+            let value: OwnedValue = self
+                .proxy
+                .get_property("ActiveProfile")
+                .await
+                .context("read ActiveProfile")?;
+            let active = decode_profile_name(&value).context("decode ActiveProfile")?;
+            Ok(self.without_holds(active).await)
+        })
+    }
+
+    fn available_profiles(&self) -> BoxFuture<'_, Result<Vec<String>>> {
+        Box::pin(async move {
+            // aa{sv}: one dict per profile, the name under "Profile".
+            let profiles: Vec<HashMap<String, OwnedValue>> = self
+                .proxy
+                .get_property("Profiles")
+                .await
+                .context("read Profiles")?;
+            profiles
+                .iter()
+                .map(|entry| {
+                    let name = entry
+                        .get("Profile")
+                        .ok_or_else(|| anyhow!("Profiles entry without a 'Profile' key"))?;
+                    decode_profile_name(name).context("decode Profiles")
+                })
+                .collect()
+        })
+    }
+
+    fn next_change(&mut self) -> BoxFuture<'_, Option<String>> {
+        Box::pin(async move {
+            while let Some(signal) = self.stream.next().await {
+                if let Some(name) = ppd_changed_profile(&signal, &self.bus.interface) {
+                    return Some(self.without_holds(name).await);
+                }
+            }
+            None
+        })
+    }
+}
+
+/// `ActiveProfile` from a PPD PropertiesChanged signal on `interface`, if it
+/// carries one.
+fn ppd_changed_profile(signal: &PropertiesChanged, interface: &str) -> Option<String> {
+    let args = match signal.args() {
+        Ok(a) => a,
+        Err(e) => {
+            warn!("signal args decode failed: {e}");
+            return None;
+        }
+    };
+
+    if !should_handle(args.interface_name(), interface) {
+        return None;
+    }
+
+    let changed: &HashMap<&str, Value> = args.changed_properties();
+    match decode_profile_name(changed.get("ActiveProfile")?) {
+        Ok(name) => Some(name),
+        Err(e) => {
+            warn!("ActiveProfile in PropertiesChanged: {e:#}");
+            None
+        }
+    }
+}
+
+/// TuneD: `active_profile()` method and the `profile_changed` signal.
+struct TunedSource {
+    proxy: Proxy<'static>,
+    stream: SignalStream<'static>,
+}
+
+impl TunedSource {
+    async fn connect(conn: &Connection) -> Result<Self> {
+        let proxy = Proxy::new(conn, TUNED_DEST, TUNED_PATH, TUNED_IFACE)
+            .await
+            .context("create TuneD proxy")?;
+        let stream = proxy
+            .receive_signal("profile_changed")
+            .await
+            .context("subscribe TuneD profile_changed")?;
+        Ok(Self { proxy, stream })
+    }
+}
+
+impl ProfileSource for TunedSource {
+    fn active_profile(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(async move {
+            self.proxy
+                .call("active_profile", &())
+                .await
+                .context("call TuneD active_profile")
+        })
+    }
+
+    fn available_profiles(&self) -> BoxFuture<'_, Result<Vec<String>>> {
+        Box::pin(async move {
+            self.proxy
+                .call("profiles", &())
+                .await
+                .context("call TuneD profiles")
+        })
+    }
+
+    fn next_change(&mut self) -> BoxFuture<'_, Option<String>> {
+        Box::pin(async move {
+            while let Some(msg) = self.stream.next().await {
+                // profile_changed(s new_profile, b result, s message)
+                match msg.body().deserialize::<(String, bool, String)>() {
+                    Ok((name, true, _)) => return Some(name),
+                    Ok((name, false, message)) => {
+                        warn!("[tuned] switch to '{name}' failed: {message}");
+                    }
+                    Err(e) => warn!("[tuned] profile_changed decode failed: {e}"),
+                }
+            }
+            None
+        })
+    }
+}
+
+/// Read the source's active profile. `Ok(None)` (logged) when it doesn't map
+/// to a `Profile`. `phase` only tags the log lines.
+pub async fn read_active_profile(
+    session: &Session,
+    config: &Config,
+    phase: &str,
+) -> Result<Option<Profile>> {
+    let current_raw = session.source.active_profile().await?;
+    if current_raw.trim().is_empty() {
+        log_no_active_profile(config, phase);
+        return Ok(None);
+    }
+    match config.resolve_profile(&current_raw) {
+        Ok(p) => {
+            info!(profile = ?p, "[{phase}] ActiveProfile");
+            Ok(Some(p))
+        }
+        Err(e) => {
+            warn!("[{phase}] {e}");
+            Ok(None)
+        }
+    }
+}
+
+/// `read_active_profile` at startup, retried with backoff while the source
+/// is merely not ready yet (see `NOT_READY_ERRORS`). Any other error, or one
+/// that outlasts `STARTUP_READ_ATTEMPTS`, is returned.
+pub async fn read_startup_profile(session: &Session, config: &Config) -> Result<Option<Profile>> {
+    let mut delay = STARTUP_READ_INITIAL_DELAY;
+    let mut attempt = 1;
+    loop {
+        match read_active_profile(session, config, "startup").await {
+            Err(e) if source_not_ready(&e) => {
+                if attempt == STARTUP_READ_ATTEMPTS {
+                    return Err(e.context(format!(
+                        "ActiveProfile still unavailable after {attempt} attempts"
+                    )));
+                }
+                warn!(attempt, "[startup] {e:#}; retrying in {delay:?}");
+                ping_watchdog();
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether `e` carries one of `NOT_READY_ERRORS`.
+fn source_not_ready(e: &anyhow::Error) -> bool {
+    fn zbus_name(e: &zbus::Error) -> Option<String> {
+        match e {
+            zbus::Error::MethodError(name, _, _) => Some(name.to_string()),
+            zbus::Error::FDO(e) => fdo_name(e),
+            _ => None,
+        }
+    }
+    fn fdo_name(e: &zbus::fdo::Error) -> Option<String> {
+        match e {
+            zbus::fdo::Error::ZBus(e) => zbus_name(e),
+            e => Some(zbus::DBusError::name(e).to_string()),
+        }
+    }
+    e.chain()
+        .find_map(|cause| match cause.downcast_ref::<zbus::fdo::Error>() {
+            Some(e) => fdo_name(e),
+            None => cause.downcast_ref::<zbus::Error>().and_then(zbus_name),
+        })
+        .is_some_and(|name| NOT_READY_ERRORS.contains(&name.as_str()))
+}
+
+/// An empty profile name, as power-profiles-daemon briefly reports while it
+/// restarts: not an unknown profile, just nothing to apply yet.
+pub fn log_no_active_profile(config: &Config, phase: &str) {
+    let source = match config.source {
+        SourceKind::Ppd => "PowerProfiles",
+        SourceKind::Tuned => "TuneD",
+    };
+    info!("[{phase}] {source} reported no active profile, waiting");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_are_filtered_by_exact_interface() {
+        let (legacy, current) = (PPD_CANDIDATES[1].2, PPD_CANDIDATES[0].2);
+        assert!(should_handle(legacy, legacy));
+        assert!(should_handle(current, current));
+        assert!(!should_handle(legacy, current));
+        assert!(!should_handle(current, legacy));
+        assert!(!should_handle("org.freedesktop.DBus.Properties", legacy));
+        assert!(!should_handle("net.hadess.powerprofiles", legacy));
+        assert!(!should_handle("", UPOWER_IFACE));
+    }
+}
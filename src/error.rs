@@ -0,0 +1,222 @@
+//! Errors returned by the library, so an embedder can tell a bad config from
+//! a failed switch without matching on messages. The binaries wrap them in
+//! `anyhow` for reporting; `{:#}` there shows the whole chain.
+
+use crate::config::DEFAULT_MODE_KEY;
+use crate::{Profile, SourceKind};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Finding, reading or validating the configuration, or looking something
+/// up in it.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The file given with `--config` doesn't exist.
+    #[error("configuration file not found: {}", .0.display())]
+    MissingFile(PathBuf),
+    /// None of `config_search_paths` exists.
+    #[error("configuration file not found; looked in: {}", list(.searched))]
+    NotFound { searched: Vec<PathBuf> },
+    #[error("read configuration {}", .path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// Not YAML, or not shaped like a config (wrong types, unknown fields).
+    #[error("parse {}", .path.display())]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: serde_yaml::Error,
+    },
+    /// Well-formed, but rejected: a duplicate entry, an empty `sched`, an
+    /// unset `${VAR}`, an unsupported `version`... `message` says what and
+    /// where.
+    #[error("{message}")]
+    Invalid { path: PathBuf, message: String },
+    /// `require_all_profiles` is on and `profile` has no mode (in `preset`,
+    /// when set).
+    #[error(
+        "configuration {}{} missing profile '{}' (and no '{DEFAULT_MODE_KEY}' mode)",
+        .path.display(),
+        .preset.as_ref().map_or(String::new(), |name| format!(" preset '{name}'")),
+        .profile.as_config_key()
+    )]
+    MissingProfile {
+        path: PathBuf,
+        preset: Option<String>,
+        profile: Profile,
+    },
+    /// Not `performance`, `balanced` or `power-saver`.
+    #[error("unknown power profile: {0}")]
+    UnknownProfile(String),
+    /// The profile source reported a name that neither is a profile nor is
+    /// mapped to one (`aliases`, `tuned_profiles`).
+    #[error("{}", unmapped(.name, *.from))]
+    UnmappedProfile { name: String, from: SourceKind },
+    #[error("unknown preset '{0}'")]
+    UnknownPreset(String),
+    /// With `strict`, schedulers the config names but `scxctl list` lacks.
+    #[error("unknown scheduler(s): {}", .0.join(", "))]
+    UnknownSchedulers(Vec<String>),
+}
+
+impl ConfigError {
+    pub(crate) fn invalid(path: &Path, message: impl Into<String>) -> Self {
+        ConfigError::Invalid {
+            path: path.to_owned(),
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn parse(path: &Path, source: serde_yaml::Error) -> Self {
+        ConfigError::Parse {
+            path: path.to_owned(),
+            source,
+        }
+    }
+}
+
+fn list(paths: &[PathBuf]) -> String {
+    if paths.is_empty() {
+        return "<none>".to_owned();
+    }
+    let paths: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+    paths.join(", ")
+}
+
+fn unmapped(name: &str, from: SourceKind) -> String {
+    match from {
+        SourceKind::Ppd => format!(
+            "unknown power profile: {name} (add it to `aliases` to map it to a known profile)"
+        ),
+        SourceKind::Tuned => format!("TuneD profile '{name}' has no entry in tuned_profiles"),
+    }
+}
+
+/// Running scxctl or a mode's hooks.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ApplyError {
+    /// Not at the configured path, or (for a bare name) not in PATH.
+    #[error(
+        "required binary not found{}: {}",
+        if *.in_path { " in PATH" } else { "" },
+        .bin.display()
+    )]
+    BinaryNotFound { bin: PathBuf, in_path: bool },
+    /// scxctl couldn't be started.
+    #[error("failed to exec {}", .bin.display())]
+    Exec {
+        bin: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// An `Exec` failure because nothing is at `bin` any more, though
+    /// `ensure_bin` found it at startup; `back` if it has reappeared since.
+    #[error("{}", gone(.bin, *.back))]
+    BinaryGone {
+        bin: PathBuf,
+        back: bool,
+        #[source]
+        source: Box<ApplyError>,
+    },
+    /// scxctl didn't exit within `scxctl_timeout_ms` and was killed.
+    #[error("scxctl {subcmd} timed out after {after:?}")]
+    Timeout { subcmd: String, after: Duration },
+    /// scxctl ran and exited non-zero; for start/switch, after `retries`.
+    #[error("scxctl {subcmd} failed (exit={code}): {stderr}")]
+    Scxctl {
+        subcmd: String,
+        code: i32,
+        stderr: String,
+    },
+    /// The `scxctl get` an apply starts with failed.
+    #[error("probe scx running")]
+    Probe(#[source] Box<ApplyError>),
+    /// `force_subcmd: start` with a scheduler running, and stopping it failed.
+    #[error("force_subcmd is start")]
+    StopFirst(#[source] Box<ApplyError>),
+    /// A pre hook failed, so scxctl wasn't run.
+    #[error("pre hook failed; not switching")]
+    PreHook(#[source] Box<ApplyError>),
+    #[error("failed to exec {phase} hook: {cmd}")]
+    HookExec {
+        phase: &'static str,
+        cmd: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{phase} hook exited with {code}: {cmd}")]
+    HookFailed {
+        phase: &'static str,
+        cmd: String,
+        code: i32,
+    },
+}
+
+impl ApplyError {
+    /// Whether this, or what it wraps, is scxctl failing to start because
+    /// nothing is at its path.
+    pub(crate) fn is_exec_not_found(&self) -> bool {
+        match self {
+            ApplyError::Exec { source, .. } => source.kind() == io::ErrorKind::NotFound,
+            ApplyError::Probe(inner) | ApplyError::StopFirst(inner) => inner.is_exec_not_found(),
+            _ => false,
+        }
+    }
+}
+
+fn gone(bin: &Path, back: bool) -> String {
+    if back {
+        format!(
+            "{} was missing but is back (replaced during an upgrade?); the next apply should work",
+            bin.display()
+        )
+    } else {
+        format!(
+            "{} has gone missing since startup (removed by a package upgrade?); \
+             reinstall it or point scxctl_path at it",
+            bin.display()
+        )
+    }
+}
+
+/// A profile value from D-Bus that isn't usable.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum DecodeError {
+    #[error("profile has D-Bus type '{0}', expected a string")]
+    NotAString(String),
+    /// power-profiles-daemon reported an empty `ActiveProfile`, as it does
+    /// briefly while it restarts.
+    #[error("no active profile reported")]
+    NoActiveProfile,
+    /// A name the config doesn't map to a profile.
+    #[error(transparent)]
+    Unmapped(#[from] ConfigError),
+}
+
+/// `e` and its sources as "outer: inner: ...", the way `anyhow` prints
+/// `{:#}`, for the library's own log lines.
+pub(crate) fn chain(e: &dyn Error) -> impl fmt::Display + '_ {
+    struct Chain<'a>(&'a dyn Error);
+    impl fmt::Display for Chain<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0)?;
+            let mut cause = self.0.source();
+            while let Some(e) = cause {
+                write!(f, ": {e}")?;
+                cause = e.source();
+            }
+            Ok(())
+        }
+    }
+    Chain(e)
+}
@@ -0,0 +1,84 @@
+//! The last few applies and how they went, for `History` on the status
+//! interface. Sized by `history_size` in the config.
+
+use scx_power_sync_dbus::Profile;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One finished apply.
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+    profile: Profile,
+    sched: String,
+    /// `None` when the apply succeeded.
+    error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct History {
+    inner: Mutex<Ring>,
+}
+
+#[derive(Debug, Default)]
+struct Ring {
+    entries: VecDeque<Entry>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        let history = History::default();
+        history.set_capacity(capacity);
+        history
+    }
+
+    /// Keep at most `capacity` entries from now on, dropping the oldest if
+    /// there are more already; zero turns the history off.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut ring = self.inner.lock().unwrap();
+        ring.capacity = capacity;
+        let excess = ring.entries.len().saturating_sub(capacity);
+        ring.entries.drain(..excess);
+    }
+
+    /// Note that applying `sched` for `profile` just finished with `error`;
+    /// `sched` is `Mode::sched_label`, so a stop mode shows up as `stop`.
+    pub fn record(&self, profile: Profile, sched: &str, error: Option<String>) {
+        let mut ring = self.inner.lock().unwrap();
+        if ring.capacity == 0 {
+            return;
+        }
+        if ring.entries.len() == ring.capacity {
+            ring.entries.pop_front();
+        }
+        ring.entries.push_back(Entry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            profile,
+            sched: sched.to_owned(),
+            error,
+        });
+    }
+
+    /// Oldest first, as `(timestamp, profile, sched, ok, error)`; `error` is
+    /// "" for a successful apply.
+    pub fn entries(&self) -> Vec<(u64, String, String, bool, String)> {
+        let ring = self.inner.lock().unwrap();
+        ring.entries
+            .iter()
+            .map(|e| {
+                (
+                    e.timestamp,
+                    e.profile.as_config_key().to_owned(),
+                    e.sched.clone(),
+                    e.error.is_none(),
+                    e.error.clone().unwrap_or_default(),
+                )
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,269 @@
+//! Config parsing and scxctl control behind `scx-power-sync-dbus`, for
+//! embedding in other power-management daemons. The binary adds the D-Bus
+//! side (profile sources, UPower, the status interface) on top of this.
+
+use serde::Deserialize;
+use std::str::FromStr;
+use zvariant::Value;
+
+#[cfg(not(any(feature = "async", feature = "blocking")))]
+compile_error!("enable the `async` (default) or the `blocking` feature");
+
+mod config;
+mod error;
+mod rt;
+mod scx;
+pub use config::{
+    BusKind, CONFIG_VERSION, Config, MissingSchedExt, ModeSet, Preset, Selector, SourceKind,
+    config_path, config_search_paths, load_config, load_merged_config, migrate_config,
+};
+pub use error::{ApplyError, ConfigError, DecodeError};
+#[cfg(feature = "blocking")]
+pub use rt::block_on;
+pub use scx::{
+    ApplyOptions, ProcessRunner, ScxRunner, ScxSettings, ScxStatus, ScxctlVersion, apply_mode,
+    apply_mode_with_status, check_sched_args, check_schedulers, detect_scxctl_version, ensure_bin,
+    probe_for_apply, sched_ext_available, scx_running, scx_schedulers, scx_status, stop_scheduler,
+};
+
+/// Filled in by `Mode::for_profile`.
+const PROFILE_PLACEHOLDER: &str = "{profile}";
+
+/// A power-profiles-daemon profile; also the keys under `modes:`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Profile {
+    Performance,
+    Balanced,
+    PowerSaver,
+}
+
+impl Profile {
+    /// The PPD / config spelling, e.g. `power-saver`.
+    pub fn as_config_key(self) -> &'static str {
+        match self {
+            Profile::Performance => "performance",
+            Profile::Balanced => "balanced",
+            Profile::PowerSaver => "power-saver",
+        }
+    }
+
+    /// Every profile, in PPD's order from fastest to most frugal.
+    pub fn all() -> [Profile; 3] {
+        [Profile::Performance, Profile::Balanced, Profile::PowerSaver]
+    }
+}
+
+impl FromStr for Profile {
+    type Err = ConfigError;
+    fn from_str(s: &str) -> Result<Self, ConfigError> {
+        match s {
+            "performance" => Ok(Self::Performance),
+            "balanced" => Ok(Self::Balanced),
+            "power-saver" => Ok(Self::PowerSaver),
+            other => Err(ConfigError::UnknownProfile(other.to_owned())),
+        }
+    }
+}
+
+/// Decode power-profiles-daemon's `ActiveProfile`, as it arrives from a
+/// property read or a PropertiesChanged signal, into the `Profile` it maps
+/// to under `config` (`Config::resolve_profile`, so aliases count).
+pub fn decode_active_profile(val: &Value, config: &Config) -> Result<Profile, DecodeError> {
+    match decode_profile_name(val)?.as_str() {
+        "" => Err(DecodeError::NoActiveProfile),
+        name => Ok(config.resolve_profile(name)?),
+    }
+}
+
+/// The string inside `val`, with any nesting of variants unwrapped and
+/// surrounding whitespace trimmed. Empty when power-profiles-daemon has no
+/// active profile, as happens briefly while it restarts.
+pub fn decode_profile_name(val: &Value) -> Result<String, DecodeError> {
+    let mut val = val;
+    while let Value::Value(inner) = val {
+        val = inner;
+    }
+    match val {
+        Value::Str(s) => Ok(s.as_str().trim().to_owned()),
+        other => Err(DecodeError::NotAString(other.value_signature().to_string())),
+    }
+}
+
+/// A scheduler plus the arguments and hooks to run it with.
+#[derive(Debug, Clone)]
+pub struct Mode {
+    /// Run `sched`, or stop scx altogether.
+    pub action: ModeAction,
+    /// Scheduler name as passed to `scxctl --sched`; empty for
+    /// `ModeAction::Stop`.
+    pub sched: String,
+    /// Joined with spaces and passed as `--args=<joined>`. May hold
+    /// `{profile}`; see `for_profile`.
+    pub args: Vec<String>,
+    /// Shell commands run before scxctl; failure aborts.
+    pub pre: Vec<String>,
+    /// Shell commands run after scxctl; failure only warns.
+    pub post: Vec<String>,
+    /// cpufreq energy_performance_preference written to every CPU that has one.
+    pub epp: Option<String>,
+    /// Free-form label from the config, e.g. "low-latency gaming tune".
+    pub description: Option<String>,
+    /// Overrides the running-based choice between `start` and `switch`.
+    pub force_subcmd: Option<ScxSubcommand>,
+    /// How `args` are put on the scxctl command line.
+    pub args_style: ArgsStyle,
+    /// Set for this mode's hooks and its scxctl start/switch, on top of the
+    /// daemon's environment; `None` removes the variable. Sorted by name.
+    pub env: Vec<(String, Option<String>)>,
+}
+
+/// What applying a mode does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModeAction {
+    /// Start or switch to the mode's scheduler.
+    #[default]
+    Run,
+    /// `scxctl stop`, handing scheduling back to the kernel.
+    Stop,
+}
+
+impl ModeAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ModeAction::Run => "run",
+            ModeAction::Stop => "stop",
+        }
+    }
+}
+
+/// How a mode's args reach scxctl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArgsStyle {
+    /// One `--args=<tokens joined by spaces>` argument, re-split by scxctl.
+    #[default]
+    Joined,
+    /// `--args` followed by each token as its own argument.
+    List,
+}
+
+/// The scxctl subcommand that makes a mode the running scheduler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScxSubcommand {
+    /// Load the scheduler fresh; whatever is running is stopped first.
+    Start,
+    /// Replace the running scheduler in place.
+    Switch,
+}
+
+impl ScxSubcommand {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ScxSubcommand::Start => "start",
+            ScxSubcommand::Switch => "switch",
+        }
+    }
+}
+
+impl Mode {
+    /// This mode as applied for `profile`: `{profile}` in `args` replaced
+    /// with its config key. The daemon applies and compares modes in this
+    /// form, so a library caller passing a mode to `apply_mode` should too.
+    pub fn for_profile(&self, profile: Profile) -> Mode {
+        Mode {
+            args: self
+                .args
+                .iter()
+                .map(|arg| arg.replace(PROFILE_PLACEHOLDER, profile.as_config_key()))
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// The scheduler, or `stop` for an `action: stop` mode, which has none.
+    pub fn sched_label(&self) -> &str {
+        match self.action {
+            ModeAction::Stop => "stop",
+            ModeAction::Run => &self.sched,
+        }
+    }
+
+    /// One-line `sched --args=...` rendering for logs and summaries.
+    pub fn describe(&self) -> String {
+        let mut out = if self.action == ModeAction::Stop {
+            "stop scx (action: stop)".to_owned()
+        } else if self.args.is_empty() {
+            self.sched.clone()
+        } else {
+            format!("{} --args={}", self.sched, self.args.join(" "))
+        };
+        if let Some(epp) = &self.epp {
+            out.push_str(&format!(" (epp: {epp})"));
+        }
+        if let Some(subcmd) = self.force_subcmd {
+            out.push_str(&format!(" (force_subcmd: {})", subcmd.as_str()));
+        }
+        if self.args_style == ArgsStyle::List {
+            out.push_str(" (args_style: list)");
+        }
+        if let Some(description) = &self.description {
+            out.push_str(&format!(" — {description}"));
+        }
+        out
+    }
+}
+
+/// power-profiles-daemon's (name, path, interface), newest first, for
+/// picking a `PpdBus` when `ppd_dbus` isn't set.
+pub const PPD_CANDIDATES: [(&str, &str, &str); 2] = [
+    (
+        "org.freedesktop.UPower.PowerProfiles",
+        "/org/freedesktop/UPower/PowerProfiles",
+        "org.freedesktop.UPower.PowerProfiles",
+    ),
+    (
+        "net.hadess.PowerProfiles",
+        "/net/hadess/PowerProfiles",
+        "net.hadess.PowerProfiles",
+    ),
+];
+
+/// D-Bus name, object path and interface of power-profiles-daemon, which
+/// moved from `net.hadess.PowerProfiles` to `org.freedesktop.UPower.PowerProfiles`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PpdBus {
+    pub name: String,
+    pub path: String,
+    pub interface: String,
+}
+
+/// Whether the machine runs on mains or battery, per UPower's `OnBattery`.
+/// Assumed `Ac` when UPower isn't watched or isn't available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PowerSource {
+    #[default]
+    Ac,
+    Battery,
+}
+
+impl PowerSource {
+    /// Map UPower's `OnBattery` property.
+    pub fn from_on_battery(on_battery: bool) -> Self {
+        if on_battery {
+            PowerSource::Battery
+        } else {
+            PowerSource::Ac
+        }
+    }
+
+    /// Key used for this variant in the config file.
+    pub fn as_config_key(self) -> &'static str {
+        match self {
+            PowerSource::Ac => "ac",
+            PowerSource::Battery => "battery",
+        }
+    }
+}
@@ -1,367 +1,2189 @@
+mod applier;
+mod dbus;
+mod history;
+mod metrics;
+mod notifications;
+mod pid_file;
+mod state_file;
+
 use anyhow::anyhow;
 use anyhow::{Context, Result};
-use futures_util::StreamExt;
-use serde::Deserialize;
+use applier::{Applier, Finished, Job};
+use clap::Parser;
+use dbus::{
+    Request, STATUS_NAME, STATUS_PATH, Session, log_no_active_profile, next_degraded_change,
+    next_power_change, next_sleep_change, read_active_profile, read_startup_profile,
+};
+use history::History;
+use metrics::Metrics;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use pid_file::PidFile;
+use scx_power_sync_dbus::{
+    ApplyOptions, BusKind, Config, MissingSchedExt, Mode, PowerSource, Profile, SourceKind,
+    apply_mode, check_sched_args, check_schedulers, config_path, detect_scxctl_version, ensure_bin,
+    load_config, load_merged_config, migrate_config, sched_ext_available, scx_schedulers,
+    scx_status, stop_scheduler,
+};
 use std::collections::HashMap;
-use std::env;
-use std::ffi::OsString;
-use std::fs;
+use std::fmt;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output};
-use std::str::FromStr;
-use tracing::{error, info, warn};
-use zbus::fdo::PropertiesProxy;
-use zbus::{Connection, Proxy};
-use zvariant::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{Instrument, debug, error, info, info_span, warn};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use zbus::Connection;
+
+/// Editors often save in several steps (write, rename, chmod); wait for quiet.
+const CONFIG_WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+/// Daily `--log-file` files kept before the oldest is deleted.
+const LOG_FILES_KEPT: usize = 7;
+/// Repeats of the same apply failure within this window are only counted.
+const FAILURE_LOG_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Load this configuration file instead of searching the default locations.
+    #[arg(long, value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+
+    /// Layer every config found in the search locations, /etc first and
+    /// ~/.config last, instead of using only the first one.
+    #[arg(long, global = true, conflicts_with = "config")]
+    merge_configs: bool,
+
+    /// Log the scxctl commands that would run instead of executing them.
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Re-apply modes even when scxctl reports the same scheduler and args.
+    #[arg(long, global = true)]
+    force: bool,
+
+    /// Re-apply the startup mode even when scxctl already reports it running,
+    /// restarting the scheduler once; later switches still skip a match.
+    #[arg(long)]
+    force_startup_apply: bool,
+
+    /// Don't apply anything at startup; only note the current profile, so
+    /// the scheduler is left alone until the next profile change.
+    #[arg(long, conflicts_with = "force_startup_apply")]
+    no_startup_sync: bool,
+
+    /// Wait MS milliseconds before the startup sync, overriding
+    /// `startup_delay_ms`; profile changes meanwhile are still picked up.
+    #[arg(long, value_name = "MS")]
+    startup_delay_ms: Option<u64>,
+
+    /// Parse and validate the configuration, print a summary, and exit. Does
+    /// not touch D-Bus or scxctl.
+    #[arg(long)]
+    verify_config: bool,
+
+    /// Print the configuration as the daemon would run with it (defaults
+    /// filled in, variables expanded, layers merged, presets resolved) and
+    /// exit. Does not touch D-Bus or scxctl.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "yaml",
+        conflicts_with = "verify_config"
+    )]
+    print_config: Option<ConfigFormat>,
+
+    /// Treat configured schedulers missing from `scxctl list` as a fatal error.
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Reload automatically when the config file changes on disk.
+    #[arg(long)]
+    watch_config: bool,
+
+    /// Show a desktop notification (org.freedesktop.Notifications on the
+    /// session bus) when switching modes fails.
+    #[arg(long)]
+    notify_on_error: bool,
 
-const DEST: &str = "net.hadess.PowerProfiles";
-const PATH: &str = "/net/hadess/PowerProfiles";
-const IFACE: &str = "net.hadess.PowerProfiles";
-const CONFIG_DIR_NAME: &str = "scx-power-sync-dbus";
-const CONFIG_FILE_NAME: &str = "config.yaml";
+    /// Look up FROM's mode as if TO were active, e.g. `balanced=performance`,
+    /// to try a mode without changing the power profile. Repeatable.
+    #[arg(long, value_name = "FROM=TO", value_parser = parse_profile_mapping, global = true)]
+    profile_map: Vec<(Profile, Profile)>,
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-enum Profile {
-    Performance,
-    Balanced,
-    PowerSaver,
+    /// Write logs to PATH instead of stderr, rotated daily to PATH.YYYY-MM-DD.
+    #[arg(long, value_name = "PATH", global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Write the daemon's PID here and refuse to start while it names a
+    /// running instance [default: $XDG_RUNTIME_DIR/scx-power-sync-dbus.pid].
+    #[arg(long, value_name = "PATH", global = true)]
+    pidfile: Option<PathBuf>,
+
+    /// Serve Prometheus metrics at http://ADDR/metrics (e.g. 127.0.0.1:9830).
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    #[command(subcommand)]
+    command: Option<Action>,
+}
+
+/// Output format of `--print-config`.
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum ConfigFormat {
+    Yaml,
+    Json,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Action {
+    /// Apply the mode for the current ActiveProfile once and exit.
+    Sync,
+    /// Print the current profile, its configured mode and the running
+    /// scheduler; exit non-zero if they differ.
+    Status,
+    /// Print the profiles the profile source offers and which of them the
+    /// config has modes for, flagging mismatches.
+    ListProfiles,
+    /// Apply the mode configured for PROFILE and exit, whatever the profile
+    /// source reports. Does not touch D-Bus.
+    Apply {
+        /// performance, balanced or power-saver.
+        profile: Profile,
+        /// Use the `battery` variant of an ac/battery mode.
+        #[arg(long)]
+        battery: bool,
+    },
+    /// Rewrite the config to the current schema (args as lists, `version`
+    /// set) and print it.
+    Migrate {
+        /// Overwrite the config file instead, keeping the original as `.bak`.
+        #[arg(long)]
+        in_place: bool,
+    },
+    /// Ask the running daemon to reload its config, through the
+    /// `ReloadConfig` method of its D-Bus interface or else SIGHUP to the
+    /// PID in its PID file.
+    Reload,
+    /// Run the startup checks one by one and report what to fix; exit
+    /// non-zero if any check that would stop the daemon fails.
+    Doctor,
+    /// Apply each profile's mode in turn and check that scxctl reports it
+    /// running after a dwell, then restore the current profile's mode; exit
+    /// non-zero if any mode fails. Switches schedulers for real.
+    TestModes {
+        /// How long each mode runs before it is checked.
+        #[arg(long, value_name = "MS", default_value_t = 3000)]
+        dwell_ms: u64,
+        /// Go ahead, knowing that scheduling is disrupted meanwhile.
+        #[arg(long)]
+        yes: bool,
+    },
 }
 
-impl Profile {
-    fn as_config_key(self) -> &'static str {
-        match self {
-            Profile::Performance => "performance",
-            Profile::Balanced => "balanced",
-            Profile::PowerSaver => "power-saver",
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let _log_guard = init_logging(cli.log_file.as_deref())?;
+
+    if let Some(Action::Migrate { in_place }) = cli.command {
+        return run_migrate(cli.config.as_deref(), in_place);
+    }
+    if let Some(Action::Reload) = cli.command {
+        return run_reload(cli.pidfile.as_deref()).await;
+    }
+    if let Some(Action::Doctor) = cli.command {
+        return run_doctor(&cli).await;
+    }
+
+    if cli.verify_config {
+        let config = load_cli_config(&cli).context("load configuration")?;
+        for problem in check_sched_args(&config) {
+            warn!("{problem}");
+        }
+        print!("{}", config.summary());
+        return Ok(());
+    }
+    if let Some(format) = cli.print_config {
+        let mut config = load_cli_config(&cli).context("load configuration")?;
+        config.profile_map = cli.profile_map.iter().copied().collect();
+        let effective = config.effective();
+        match format {
+            ConfigFormat::Yaml => print!("{}", serde_yaml::to_string(&effective)?),
+            ConfigFormat::Json => println!("{}", serde_json::to_string_pretty(&effective)?),
+        }
+        return Ok(());
+    }
+
+    let config = load_checked_config(&cli)
+        .await
+        .context("load configuration")?;
+
+    let opts = ApplyOptions {
+        dry_run: cli.dry_run,
+        force: cli.force,
+    };
+
+    if opts.dry_run {
+        info!("dry-run: scxctl start/switch will be logged, not executed");
+    } else {
+        ensure_bin(&config.scx.bin)?;
+        let uses_bus = !matches!(cli.command, Some(Action::Apply { .. }));
+        if uses_bus && config.source == SourceKind::Ppd {
+            ensure_bin(&config.powerprofilesctl)?;
         }
     }
 
-    fn all() -> [Profile; 3] {
-        [Profile::Performance, Profile::Balanced, Profile::PowerSaver]
+    match cli.command {
+        Some(Action::Sync) => run_sync(&config, &opts).await,
+        Some(Action::Status) => run_status(&config).await,
+        Some(Action::ListProfiles) => run_list_profiles(&config).await,
+        Some(Action::Apply { profile, battery }) => {
+            run_apply(
+                &config,
+                &opts,
+                profile,
+                PowerSource::from_on_battery(battery),
+            )
+            .await
+        }
+        Some(Action::TestModes { dwell_ms, yes }) => {
+            run_test_modes(&config, &opts, Duration::from_millis(dwell_ms), yes).await
+        }
+        Some(Action::Migrate { .. } | Action::Reload | Action::Doctor) => {
+            unreachable!("handled before loading the config")
+        }
+        None => run_daemon(&cli, config, opts).await,
     }
 }
 
-impl FromStr for Profile {
-    type Err = anyhow::Error;
-    fn from_str(s: &str) -> Result<Self> {
-        match s {
-            "performance" => Ok(Self::Performance),
-            "balanced" => Ok(Self::Balanced),
-            "power-saver" => Ok(Self::PowerSaver),
-            other => Err(anyhow!("unknown power profile: {other}")),
+/// One-shot reconciliation: apply the mode for the current profile and exit.
+async fn run_sync(config: &Config, opts: &ApplyOptions) -> Result<()> {
+    let session = Session::connect(config, Duration::ZERO).await?;
+    let p = read_active_profile(&session, config, "sync")
+        .await?
+        .ok_or_else(|| anyhow!("could not resolve the current ActiveProfile"))?;
+    match handle_profile(config, session.power, &session.degraded, p, &mut None).await {
+        Handling::Apply(mode) => Ok(apply_mode(&mode, &config.scx, opts)
+            .instrument(apply_span(p))
+            .await?),
+        Handling::Unchanged | Handling::LeftAlone => Ok(()),
+    }
+}
+
+/// Apply `profile`'s mode directly, for trying out a configured mode.
+async fn run_apply(
+    config: &Config,
+    opts: &ApplyOptions,
+    profile: Profile,
+    power: PowerSource,
+) -> Result<()> {
+    if config.mode_set_for(profile).is_none() {
+        return Err(anyhow!(
+            "no mode configured for profile '{}'",
+            profile.as_config_key()
+        ));
+    }
+    match config.choose_mode(profile, power).await {
+        Some(mode) => Ok(apply_mode(&mode.for_profile(profile), &config.scx, opts)
+            .instrument(apply_span(profile))
+            .await?),
+        None => {
+            log_unchanged(config, profile);
+            Ok(())
         }
     }
 }
 
-#[derive(Debug, Clone)]
-struct Mode {
-    sched: String,
-    args: String, // kept as a single string; passed as --args=<this>
+/// `test-modes`: for each profile, apply its mode (forced, so one that's
+/// already running is exercised too), wait `dwell` and compare `scxctl get`
+/// with it. Ends by applying the mode for the profile the source reports,
+/// as `sync` would.
+async fn run_test_modes(
+    config: &Config,
+    opts: &ApplyOptions,
+    dwell: Duration,
+    yes: bool,
+) -> Result<()> {
+    if !yes {
+        return Err(anyhow!(
+            "test-modes switches schedulers for real, one profile after another; \
+             pass --yes to go ahead"
+        ));
+    }
+    if opts.dry_run {
+        return Err(anyhow!(
+            "test-modes checks what scxctl actually runs, so it can't be combined with --dry-run"
+        ));
+    }
+    let session = Session::connect(config, Duration::ZERO).await?;
+    let original = match read_active_profile(&session, config, "test-modes").await {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("[test-modes] {e:#}; the scheduler won't be restored afterwards");
+            None
+        }
+    };
+    let forced = ApplyOptions {
+        force: true,
+        ..*opts
+    };
+    let mut checkup = Checkup::default();
+    for p in Profile::all() {
+        let key = p.as_config_key();
+        let Handling::Apply(mode) =
+            handle_profile(config, session.power, &session.degraded, p, &mut None).await
+        else {
+            println!("{}", check_line("skip", key, "no mode to apply", None));
+            continue;
+        };
+        let applied = apply_mode(&mode, &config.scx, &forced)
+            .instrument(apply_span(p))
+            .await;
+        if let Err(e) = applied {
+            let hint = "check the mode's sched and args; `scxctl start` by hand shows the scheduler's own output";
+            checkup.fail(key, format_args!("{:#}", anyhow::Error::from(e)), hint);
+            continue;
+        }
+        tokio::time::sleep(dwell).await;
+        match scx_status(&config.scx).await {
+            Ok(status) if status.agrees_with(&mode) => checkup.pass(key, mode.describe()),
+            Ok(status) => checkup.fail(
+                key,
+                format_args!(
+                    "expected {}, scxctl reports {}",
+                    mode.describe(),
+                    status.describe()
+                ),
+                "the scheduler started but didn't stay up; the scx_loader journal says why",
+            ),
+            Err(e) => checkup.fail(
+                key,
+                format_args!("{:#}", anyhow::Error::from(e)),
+                "scxctl get failed, so the mode couldn't be checked",
+            ),
+        }
+    }
+
+    if let Some(p) = original {
+        match handle_profile(config, session.power, &session.degraded, p, &mut None).await {
+            Handling::Apply(mode) => {
+                apply_mode(&mode, &config.scx, opts)
+                    .instrument(apply_span(p))
+                    .await
+                    .context("restore the active profile's mode")?;
+                println!("restored the mode for {}", p.as_config_key());
+            }
+            Handling::Unchanged | Handling::LeftAlone => println!(
+                "{} has no mode to restore; the last tested one is still running",
+                p.as_config_key()
+            ),
+        }
+    }
+    match checkup.failed {
+        0 => println!("all modes passed"),
+        failed => return Err(anyhow!("{failed} mode(s) failed")),
+    }
+    Ok(())
 }
 
-#[derive(Debug, Deserialize)]
-struct RawConfig {
-    modes: HashMap<String, ModeDefinition>,
+/// Print the migrated config, or write it over the original.
+fn run_migrate(path_override: Option<&Path>, in_place: bool) -> Result<()> {
+    let path = config_path(path_override)?;
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("read configuration {}", path.display()))?;
+    let migrated = migrate_config(&contents, &path)?;
+    if !in_place {
+        print!("{migrated}");
+        return Ok(());
+    }
+    if migrated == contents {
+        info!(config = %path.display(), "[migrate] already up to date");
+        return Ok(());
+    }
+    let mut backup = path.clone().into_os_string();
+    backup.push(".bak");
+    std::fs::copy(&path, &backup).with_context(|| format!("back up {}", path.display()))?;
+    std::fs::write(&path, migrated).with_context(|| format!("write {}", path.display()))?;
+    info!(config = %path.display(), backup = %Path::new(&backup).display(), "[migrate] rewritten");
+    Ok(())
 }
 
-#[derive(Debug, Deserialize)]
-struct ModeDefinition {
-    sched: String,
-    args: String,
+/// Call `ReloadConfig` on whichever bus the daemon serves `org.scx.PowerSync`
+/// on, falling back to SIGHUP via the PID file. Doesn't need a loadable
+/// config: fixing a broken one is usually why someone reloads.
+async fn run_reload(pidfile: Option<&Path>) -> Result<()> {
+    for bus in [BusKind::System, BusKind::Session] {
+        let conn = match bus {
+            BusKind::System => Connection::system().await,
+            BusKind::Session => Connection::session().await,
+        };
+        let conn = match conn {
+            Ok(conn) => conn,
+            Err(e) => {
+                debug!("[reload] no {} bus: {e}", bus.as_str());
+                continue;
+            }
+        };
+        let reply = conn
+            .call_method(
+                Some(STATUS_NAME),
+                STATUS_PATH,
+                Some(STATUS_NAME),
+                "ReloadConfig",
+                &(),
+            )
+            .await;
+        match reply {
+            Ok(_) => {
+                println!(
+                    "reload requested on the {} bus; the daemon logs the outcome",
+                    bus.as_str()
+                );
+                return Ok(());
+            }
+            Err(zbus::Error::MethodError(name, _, _))
+                if name.as_str() == "org.freedesktop.DBus.Error.ServiceUnknown" =>
+            {
+                debug!("[reload] {STATUS_NAME} isn't on the {} bus", bus.as_str());
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("call ReloadConfig on the {} bus", bus.as_str()));
+            }
+        }
+    }
+    if let Some(path) = pid_file::path(pidfile)
+        && let Some(pid) = pid_file::running_pid(&path)
+    {
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &pid.to_string()])
+            .status()
+            .context("run kill")?;
+        if !status.success() {
+            return Err(anyhow!("kill -HUP {pid} failed ({status})"));
+        }
+        println!("sent SIGHUP to pid {pid}; the daemon logs the outcome");
+        return Ok(());
+    }
+    Err(anyhow!(
+        "no running daemon found: {STATUS_NAME} is on neither the system nor the session bus, \
+         and no PID file names a live instance"
+    ))
 }
 
-impl From<ModeDefinition> for Mode {
-    fn from(def: ModeDefinition) -> Self {
-        Self {
-            sched: def.sched,
-            args: def.args,
+/// Print what the profile source offers next to what the config configures.
+async fn run_list_profiles(config: &Config) -> Result<()> {
+    let session = Session::connect(config, Duration::ZERO).await?;
+    let names = session.source.available_profiles().await?;
+
+    let mut available = Vec::new();
+    println!("available profiles:");
+    for name in &names {
+        let line = match config.resolve_profile(name) {
+            Ok(p) => {
+                available.push(p);
+                match config.mode_set_for(p) {
+                    Some(set) => set.describe(),
+                    None => "no mode configured".to_owned(),
+                }
+            }
+            Err(e) => format!("ignored: {e}"),
+        };
+        println!("  {name:<24} {line}");
+    }
+
+    let unavailable = unavailable_profiles(config, &available);
+    if !unavailable.is_empty() {
+        println!("configured but not offered on this machine:");
+        for p in unavailable {
+            println!("  {}", p.as_config_key());
         }
     }
+    Ok(())
 }
 
-struct Config {
-    modes: HashMap<Profile, Mode>,
+/// Configured profiles missing from `available`.
+fn unavailable_profiles(config: &Config, available: &[Profile]) -> Vec<Profile> {
+    config
+        .explicit_profiles()
+        .into_iter()
+        .filter(|p| !available.contains(p))
+        .collect()
 }
 
-impl Config {
-    fn mode_for(&self, profile: Profile) -> Option<&Mode> {
-        self.modes.get(&profile)
+/// Warn about modes that can never fire because the source doesn't offer
+/// their profile. Only a warning: what the hardware offers can change (e.g.
+/// `performance` appearing once on AC).
+async fn check_available_profiles(session: &Session, config: &Config) {
+    let names = match session.source.available_profiles().await {
+        Ok(names) => names,
+        Err(e) => {
+            debug!("[profiles] could not list available profiles: {e:#}");
+            return;
+        }
+    };
+    let available: Vec<Profile> = names
+        .iter()
+        .filter_map(|name| config.resolve_profile(name).ok())
+        .collect();
+    for p in unavailable_profiles(config, &available) {
+        warn!(
+            "[profiles] config has a mode for '{}', but this machine only offers: {}",
+            p.as_config_key(),
+            names.join(", ")
+        );
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    init_logging();
+/// Tally of `doctor`'s checks, each printed as it finishes.
+#[derive(Debug, Default)]
+struct Checkup {
+    failed: usize,
+    warned: usize,
+}
 
-    let config = load_config().context("load configuration")?;
+impl Checkup {
+    fn pass(&mut self, check: &str, detail: impl fmt::Display) {
+        println!("{}", check_line("ok", check, &detail.to_string(), None));
+    }
 
-    ensure_bin("scxctl")?;
-    ensure_bin("powerprofilesctl")?;
+    /// A failure the daemon would refuse to start (or apply anything) over.
+    fn fail(&mut self, check: &str, detail: impl fmt::Display, hint: &str) {
+        self.failed += 1;
+        println!(
+            "{}",
+            check_line("FAIL", check, &detail.to_string(), Some(hint))
+        );
+    }
 
-    // Wire into the system bus (PPD lives here).
-    let conn = Connection::system().await.context("connect system D-Bus")?;
+    /// Something the daemon only warns about.
+    fn warn(&mut self, check: &str, detail: impl fmt::Display, hint: &str) {
+        self.warned += 1;
+        println!(
+            "{}",
+            check_line("warn", check, &detail.to_string(), Some(hint))
+        );
+    }
 
-    // PPD proxy for reading ActiveProfile.
-    let ppd = Proxy::new(&conn, DEST, PATH, IFACE)
-        .await
-        .context("create PPD proxy")?;
+    /// `fail` when `fatal` (`--strict`), else `warn`.
+    fn fail_or_warn(&mut self, fatal: bool, check: &str, detail: impl fmt::Display, hint: &str) {
+        if fatal {
+            self.fail(check, detail, hint);
+        } else {
+            self.warn(check, detail, hint);
+        }
+    }
+}
+
+/// One line of the `doctor` report, with the hint indented under it.
+fn check_line(status: &str, check: &str, detail: &str, hint: Option<&str>) -> String {
+    let mut line = format!("[{status:^4}] {check:<16} {detail}");
+    if let Some(hint) = hint {
+        line.push_str(&format!("\n{:7}{:<16} hint: {hint}", "", ""));
+    }
+    line
+}
+
+/// The checks the daemon makes at startup, run to the end instead of
+/// stopping at the first failure.
+async fn run_doctor(cli: &Cli) -> Result<()> {
+    let mut checkup = Checkup::default();
+    check_everything(cli, &mut checkup).await;
+    match checkup.failed {
+        0 if checkup.warned == 0 => println!("all checks passed"),
+        0 => println!("no critical problems; {} warning(s)", checkup.warned),
+        failed => return Err(anyhow!("{failed} critical check(s) failed")),
+    }
+    Ok(())
+}
+
+async fn check_everything(cli: &Cli, checkup: &mut Checkup) {
+    let mut config = match load_cli_config(cli) {
+        Ok(config) => {
+            checkup.pass("config", config.path.display());
+            config
+        }
+        Err(e) => {
+            let hint =
+                "fix the file, or pass --config; the other checks need a config and were skipped";
+            checkup.fail("config", format_args!("{e:#}"), hint);
+            return;
+        }
+    };
+    config.profile_map = cli.profile_map.iter().copied().collect();
 
-    // Immediate sync at startup.
-    let current_raw: String = ppd
-        .get_property("ActiveProfile")
+    match ensure_bin(&config.scx.bin) {
+        Ok(()) => checkup.pass("scxctl", config.scx.bin.display()),
+        Err(e) => checkup.fail(
+            "scxctl",
+            e,
+            "install scx's scxctl, or set scxctl_path (or SCX_POWER_SYNC_SCXCTL) to its absolute path",
+        ),
+    }
+    if config.source == SourceKind::Ppd {
+        match ensure_bin(&config.powerprofilesctl) {
+            Ok(()) => checkup.pass("powerprofilesctl", config.powerprofilesctl.display()),
+            Err(e) => checkup.fail(
+                "powerprofilesctl",
+                e,
+                "install power-profiles-daemon, or set powerprofilesctl_path to its absolute path",
+            ),
+        }
+    }
+
+    let hint = "boot a kernel built with CONFIG_SCHED_CLASS_EXT (Linux 6.12 or later)";
+    match config.missing_sched_ext {
+        _ if sched_ext_available() => checkup.pass("sched_ext", "supported by the running kernel"),
+        MissingSchedExt::Exit => {
+            checkup.fail(
+                "sched_ext",
+                "the running kernel has no sched_ext support",
+                hint,
+            );
+        }
+        // The daemon still starts: it waits in standby, or leaves it to scxctl.
+        MissingSchedExt::Standby => checkup.warn(
+            "sched_ext",
+            "the running kernel has no sched_ext support; the daemon would wait in standby",
+            hint,
+        ),
+        MissingSchedExt::Ignore => checkup.warn(
+            "sched_ext",
+            "the running kernel has no sched_ext support (missing_sched_ext: ignore)",
+            hint,
+        ),
+    }
+
+    // The daemon carries on after either, unless `--strict` makes an unknown
+    // scheduler fatal; `--strict` turns both into failures here.
+    match scx_schedulers(&config.scx).await {
+        Ok(list) => {
+            checkup.pass("scxctl list", format_args!("{} scheduler(s)", list.len()));
+            match check_schedulers(&config, true).await {
+                Ok(()) => checkup.pass("schedulers", "every mode's scheduler is listed"),
+                Err(e) => checkup.fail_or_warn(
+                    cli.strict,
+                    "schedulers",
+                    e,
+                    "install the scheduler, or fix the mode's `sched`",
+                ),
+            }
+        }
+        Err(e) => checkup.fail_or_warn(
+            cli.strict,
+            "scxctl list",
+            format_args!("{:#}", anyhow::Error::from(e)),
+            "check that scx_loader is running and `scxctl list` works by hand",
+        ),
+    }
+
+    let session = match Session::connect(&config, Duration::ZERO).await {
+        Ok(session) => session,
+        Err(e) => {
+            checkup.fail(
+                "profile source",
+                format_args!("{e:#}"),
+                source_hint(&config),
+            );
+            return;
+        }
+    };
+    let names = match session.source.available_profiles().await {
+        Ok(names) => names,
+        Err(e) => {
+            checkup.fail(
+                "profile source",
+                format_args!("{e:#}"),
+                source_hint(&config),
+            );
+            return;
+        }
+    };
+    checkup.pass(
+        "profile source",
+        format_args!("{} offers: {}", source_name(&config), names.join(", ")),
+    );
+
+    let available: Vec<Profile> = names
+        .iter()
+        .filter_map(|name| config.resolve_profile(name).ok())
+        .collect();
+    let unavailable: Vec<&str> = unavailable_profiles(&config, &available)
+        .into_iter()
+        .map(Profile::as_config_key)
+        .collect();
+    if unavailable.is_empty() {
+        checkup.pass("profiles", "every configured profile is offered");
+    } else {
+        checkup.warn(
+            "profiles",
+            format_args!("configured but not offered: {}", unavailable.join(", ")),
+            "those modes can never fire; `list-profiles` shows what this machine offers",
+        );
+    }
+}
+
+fn source_name(config: &Config) -> &'static str {
+    match config.source {
+        SourceKind::Ppd => "power-profiles-daemon",
+        SourceKind::Tuned => "TuneD",
+    }
+}
+
+fn source_hint(config: &Config) -> &'static str {
+    match config.source {
+        SourceKind::Ppd => {
+            "check `systemctl status power-profiles-daemon`, and set ppd_dbus if it uses another name"
+        }
+        SourceKind::Tuned => "check `systemctl status tuned`",
+    }
+}
+
+/// Compare the mode configured for the current profile with `scxctl get`.
+async fn run_status(config: &Config) -> Result<()> {
+    let session = Session::connect(config, Duration::ZERO).await?;
+    let profile = read_active_profile(&session, config, "status")
+        .await?
+        .ok_or_else(|| anyhow!("could not resolve the current ActiveProfile"))?;
+    let expected = choose_mode(config, profile, session.power, &session.degraded)
         .await
-        .context("read ActiveProfile")?;
-    let mut last = match Profile::from_str(current_raw.as_str()) {
-        Ok(p) => {
-            info!(profile = ?p, "[startup] ActiveProfile");
-            match config.mode_for(p) {
-                Some(mode) => {
-                    apply_mode(mode)?;
-                    Some(p)
+        .map(|mode| mode.for_profile(profile));
+    let running = scx_status(&config.scx).await.context("probe scx running")?;
+
+    println!("profile:    {}", profile.as_config_key());
+    if config.watches_power_source() {
+        println!("power:      {}", session.power.as_config_key());
+    }
+    if !session.degraded.is_empty() {
+        println!("degraded:   {}", session.degraded);
+    }
+    // An entry that yields no mode is a disabled one.
+    let has_entry = config.mode_set_for(profile).is_some();
+    println!(
+        "configured: {}",
+        match &expected {
+            Some(mode) => mode.describe(),
+            None if has_entry => "disabled".to_owned(),
+            None => "<none>".to_owned(),
+        }
+    );
+    println!("running:    {}", running.describe());
+
+    match expected {
+        None if has_entry => {
+            println!("profile disabled; nothing to compare");
+            Ok(())
+        }
+        None => {
+            println!("no mode configured for this profile; nothing to compare");
+            Ok(())
+        }
+        Some(mode) if running.matches(&mode) => {
+            println!("in sync");
+            Ok(())
+        }
+        Some(_) => Err(anyhow!(
+            "running scheduler does not match the configured mode"
+        )),
+    }
+}
+
+async fn run_daemon(cli: &Cli, mut config: Config, opts: ApplyOptions) -> Result<()> {
+    // Removed again when this returns, however the daemon stops.
+    let _pid_file = pid_file::path(cli.pidfile.as_deref())
+        .map(|path| PidFile::acquire(&path))
+        .transpose()?;
+    if !opts.dry_run {
+        config.scx.version = detect_scxctl_version(&config.scx).await;
+    }
+    let standby = match config.missing_sched_ext {
+        MissingSchedExt::Ignore => false,
+        _ if sched_ext_available() => false,
+        MissingSchedExt::Exit => {
+            return Err(anyhow!(
+                "this kernel has no sched_ext support (/sys/kernel/sched_ext is missing); \
+                 boot a kernel built with CONFIG_SCHED_CLASS_EXT"
+            ));
+        }
+        MissingSchedExt::Standby => {
+            warn!(
+                "[standby] this kernel has no sched_ext support (/sys/kernel/sched_ext is missing); \
+                 ignoring profile changes until it appears"
+            );
+            true
+        }
+    };
+    let metrics = Arc::new(Metrics::default());
+    if let Some(addr) = cli.metrics_addr {
+        metrics::serve(addr, metrics.clone()).await?;
+    }
+    let session = Session::connect(&config, config.startup_wait).await?;
+    let mut daemon = Daemon::new(cli, config, opts, session, metrics, standby);
+
+    check_available_profiles(&daemon.session, &daemon.config).await;
+
+    daemon
+        .session
+        .serve_status(&daemon.requests_tx, &daemon.history)
+        .await;
+    daemon.sync_at_startup().await?;
+
+    daemon.run().await
+}
+
+/// State of the long-running event loop.
+struct Daemon<'a> {
+    cli: &'a Cli,
+    config: Config,
+    opts: ApplyOptions,
+    session: Session,
+    /// Profile whose mode is in effect (see `handle_profile`).
+    last: Option<Profile>,
+    /// The mode `last` was applied with; only meaningful while `last` is set.
+    applied: Option<Mode>,
+    /// Debounced ActiveProfile value waiting for `deadline` to pass.
+    pending: Option<Profile>,
+    deadline: Instant,
+    /// `pending` also waits for the apply task to go idle: how it is handled
+    /// depends on what the task is still applying (see `apply_pending`).
+    pending_after_applies: bool,
+    applier: Applier,
+    /// Bumped whenever the mode for an already-applied profile may have
+    /// changed (reload, power source flip), so a stale apply doesn't set `last`.
+    generation: u64,
+    /// When a mode was last applied, for `min_switch_interval`.
+    last_switch: Option<Instant>,
+    /// Fed by the D-Bus methods on `org.scx.PowerSync`.
+    requests_tx: mpsc::UnboundedSender<Request>,
+    requests_rx: mpsc::UnboundedReceiver<Request>,
+    /// Preset chosen over D-Bus; kept across reloads while it still exists.
+    selected_preset: Option<Option<String>>,
+    /// `--watch-config`: the watcher (kept alive here) and its events.
+    config_changed: Option<(RecommendedWatcher, mpsc::UnboundedReceiver<()>)>,
+    /// Debounced reload requested by the config watcher.
+    reload_at: Option<Instant>,
+    /// When `poll_interval_ms` next has the active profile re-read.
+    next_poll: Option<Instant>,
+    metrics: Arc<Metrics>,
+    /// Recent applies, shared with `org.scx.PowerSync` for `History`.
+    history: Arc<History>,
+    /// Apply failures per profile and sched; see `should_log_failure`.
+    failures: HashMap<(Profile, String), FailureLog>,
+    /// Applies that have failed since the last one that succeeded.
+    failed_in_a_row: u32,
+    /// End of the `failure_cooldown` started by the latest failure.
+    cooldown_until: Option<Instant>,
+    /// The kernel lacked sched_ext last time we looked; see `still_in_standby`.
+    standby: bool,
+}
+
+/// When a profile+sched failure was last logged in full, and how many
+/// identical ones have been held back since.
+struct FailureLog {
+    since: Instant,
+    suppressed: u32,
+}
+
+impl<'a> Daemon<'a> {
+    /// A daemon with nothing applied yet.
+    fn new(
+        cli: &'a Cli,
+        config: Config,
+        opts: ApplyOptions,
+        session: Session,
+        metrics: Arc<Metrics>,
+        standby: bool,
+    ) -> Self {
+        let history = Arc::new(History::new(config.history_size));
+        let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+        Daemon {
+            cli,
+            config,
+            opts,
+            session,
+            last: None,
+            applied: None,
+            pending: None,
+            deadline: Instant::now(),
+            pending_after_applies: false,
+            applier: Applier::spawn(),
+            generation: 0,
+            last_switch: None,
+            requests_tx,
+            requests_rx,
+            selected_preset: None,
+            config_changed: None,
+            reload_at: None,
+            next_poll: None,
+            metrics,
+            history,
+            failures: HashMap::new(),
+            failed_in_a_row: 0,
+            cooldown_until: None,
+            standby,
+        }
+    }
+}
+
+impl Daemon<'_> {
+    async fn run(&mut self) -> Result<()> {
+        let mut sighup = signal(SignalKind::hangup()).context("install SIGHUP handler")?;
+        let mut sigterm = signal(SignalKind::terminate()).context("install SIGTERM handler")?;
+        let mut sigint = signal(SignalKind::interrupt()).context("install SIGINT handler")?;
+        let mut sigusr1 = signal(SignalKind::user_defined1()).context("install SIGUSR1 handler")?;
+
+        // Pinged from this loop rather than a separate task so that a wedged
+        // event loop actually stops the pings and lets systemd restart us.
+        let mut watchdog = sd_notify::watchdog_enabled().map(|timeout| {
+            info!(?timeout, "systemd watchdog enabled");
+            tokio::time::interval(timeout / 2)
+        });
+
+        if self.cli.watch_config {
+            match watch_config(&self.config.layers) {
+                Ok(watch) => {
+                    for path in &self.config.layers {
+                        info!(config = %path.display(), "[watch] watching config file");
+                    }
+                    self.config_changed = Some(watch);
+                }
+                Err(e) => warn!("[watch] not watching the config file: {e:#}"),
+            }
+        }
+
+        if let Err(e) = sd_notify::notify(&[sd_notify::NotifyState::Ready]) {
+            warn!("sd_notify READY failed: {e}");
+        }
+        self.schedule_poll();
+
+        loop {
+            tokio::select! {
+                _ = watchdog_tick(&mut watchdog) => ping_watchdog(),
+                name = self.session.source.next_change() => match name {
+                    Some(name) => self.on_profile_event(&name),
+                    None => {
+                        warn!("[reconnect] profile change stream ended; reconnecting");
+                        let session = tokio::select! {
+                            session = Session::reconnect(&self.config) => session,
+                            _ = sigterm.recv() => break,
+                            _ = sigint.recv() => break,
+                        };
+                        self.on_reconnected(session).await;
+                    }
+                },
+                _ = tokio::time::sleep_until(self.deadline),
+                    if self.pending.is_some()
+                        && !(self.pending_after_applies && self.applier.is_busy()) =>
+                {
+                    self.apply_pending().await;
+                }
+                done = self.applier.finished() => {
+                    self.on_applied(done).await;
+                }
+                power = next_power_change(&mut self.session.upower) => {
+                    if let Some(power) = power {
+                        self.on_power_change(power);
+                    }
+                }
+                sleeping = next_sleep_change(&mut self.session.logind) => match sleeping {
+                    Some(true) => debug!("[resume] suspending"),
+                    Some(false) if self.config.reapply_on_resume => self.on_resume().await,
+                    _ => {}
+                },
+                degraded = next_degraded_change(
+                    &mut self.session.ppd_changes,
+                    self.session.ppd_bus.as_ref().map_or("", |bus| bus.interface.as_str()),
+                ) => {
+                    if let Some(degraded) = degraded {
+                        self.on_degraded_change(degraded);
+                    }
                 }
-                None => {
-                    warn!("no mode configured for profile {:?}", p);
-                    None
+                _ = sigterm.recv() => break,
+                _ = sigint.recv() => break,
+                _ = sighup.recv() => {
+                    info!("[reload] SIGHUP received");
+                    self.reload().await;
+                }
+                _ = sigusr1.recv() => self.log_state().await,
+                Some(request) = self.requests_rx.recv() => match request {
+                    Request::Reload => {
+                        info!("[reload] ReloadConfig called over D-Bus");
+                        self.reload().await;
+                    }
+                    Request::SetPreset(name) => self.set_preset(name),
+                },
+                Some(()) = config_change(&mut self.config_changed) => {
+                    self.reload_at = Some(Instant::now() + CONFIG_WATCH_DEBOUNCE);
+                }
+                _ = tokio::time::sleep_until(self.reload_at.unwrap_or_else(Instant::now)),
+                    if self.reload_at.is_some() =>
+                {
+                    self.reload_at = None;
+                    info!("[reload] config file changed on disk");
+                    self.reload().await;
+                }
+                _ = tokio::time::sleep_until(self.next_poll.unwrap_or_else(Instant::now)),
+                    if self.next_poll.is_some() =>
+                {
+                    self.poll_active_profile().await;
                 }
             }
         }
-        Err(e) => {
-            warn!("[startup] {e}");
-            None
+
+        info!("[shutdown] termination signal received");
+        let _ = sd_notify::notify(&[sd_notify::NotifyState::Stopping]);
+        // Events and D-Bus requests are no longer read from here on; dropping
+        // the name makes callers fail fast instead of queueing requests.
+        self.pending = None;
+        self.session.stop_serving().await;
+        self.drain_in_flight().await;
+        self.flush_failures();
+        if let Some(path) = &self.config.state_file {
+            state_file::remove(path);
         }
-    };
+        if self.config.stop_on_exit
+            && let Err(e) = stop_scheduler(&self.config.scx, &self.opts).await
+        {
+            error!("[shutdown] {:#}", anyhow::Error::from(e));
+        }
+        Ok(())
+    }
 
-    // Subscribe to property changes (ActiveProfile flips).
-    let props = PropertiesProxy::new(&conn, DEST, PATH)
+    /// Drop the apply still waiting and let the running one finish, up to
+    /// `shutdown_timeout`; past that it is aborted, which kills the scxctl
+    /// child (`kill_on_drop`).
+    async fn drain_in_flight(&mut self) {
+        if let Some(dropped) = self.applier.cancel_waiting() {
+            debug!(profile = ?dropped, "[shutdown] dropping the queued apply");
+        }
+        if !self.applier.is_busy() {
+            return;
+        }
+        let limit = self.config.shutdown_timeout;
+        info!(timeout = ?limit, "[shutdown] waiting for the in-flight apply to finish");
+        match tokio::time::timeout(limit, self.applier.finished()).await {
+            Ok(done) => self.on_applied(done).await,
+            Err(_) => {
+                let profile = self.applier.running().map(|(profile, _)| profile);
+                self.applier.abort();
+                self.metrics.record_apply_failure();
+                error!(
+                    ?profile,
+                    "[shutdown] apply did not finish within {limit:?}; aborted"
+                );
+            }
+        }
+    }
+
+    fn on_profile_event(&mut self, name: &str) {
+        if name.trim().is_empty() {
+            log_no_active_profile(&self.config, "event");
+            return;
+        }
+        match self.config.resolve_profile(name) {
+            Ok(p) => {
+                // Only the last value within the window gets applied.
+                self.pending = Some(p);
+                self.deadline = Instant::now() + self.config.debounce;
+            }
+            Err(e) => warn!("unknown profile in event: {e}"),
+        }
+    }
+
+    async fn apply_pending(&mut self) {
+        let Some(p) = self.pending.take() else {
+            return;
+        };
+        self.pending_after_applies = false;
+        if self.still_in_standby() {
+            debug!(profile = ?p, "[standby] ignoring profile change");
+            return;
+        }
+        if self.hold_for_cooldown(p) {
+            return;
+        }
+        // While applies are unfinished, compare against what they will leave
+        // in effect rather than `last`; one decided before a reload or power
+        // flip counts for nothing.
+        let busy = self.applier.is_busy();
+        let mut ahead = self
+            .applier
+            .target()
+            .filter(|&(_, generation)| generation == self.generation)
+            .map(|(profile, _)| profile);
+        // A selector runs here, bounded by its timeout; events arriving
+        // meanwhile just queue up.
+        let mode = match handle_profile(
+            &self.config,
+            self.session.power,
+            &self.session.degraded,
+            p,
+            if busy { &mut ahead } else { &mut self.last },
+        )
         .await
-        .context("create Properties proxy")?;
-    let mut stream = props
-        .receive_properties_changed()
+        {
+            // duplicate (or bounced back within the window); ignore
+            Handling::Unchanged => return,
+            // Whatever runs now stays in effect; wait for it to settle `last`.
+            Handling::LeftAlone if busy => {
+                self.wait_for_applies(p);
+                return;
+            }
+            Handling::LeftAlone => {
+                self.set_last(self.last, None).await;
+                return;
+            }
+            // Back to what is being applied: only the queued job needs undoing.
+            Handling::Apply(_) if busy && self.applier.running() == Some((p, self.generation)) => {
+                if let Some(dropped) = self.applier.cancel_waiting() {
+                    debug!(?dropped, next = ?p, "[event] superseded while an apply runs");
+                }
+                return;
+            }
+            // The dwell counts from the end of the running apply.
+            Handling::Apply(_) if busy && !self.config.min_switch_interval.is_zero() => {
+                self.wait_for_applies(p);
+                return;
+            }
+            Handling::Apply(mode) => mode,
+        };
+        if self.hold_for_dwell(p) {
+            return;
+        }
+        info!(profile = ?p, "[event] ActiveProfile");
+        // The apply task runs scxctl and hooks so the loop keeps draining
+        // D-Bus events meanwhile; `last` is only set in `on_applied`.
+        let replaced = self.applier.submit(Job {
+            profile: p,
+            generation: self.generation,
+            mode,
+            scx: self.config.scx.clone(),
+            opts: self.opts,
+            span: apply_span(p),
+        });
+        if let Some(dropped) = replaced {
+            debug!(
+                applying = ?self.applier.running().map(|(profile, _)| profile),
+                ?dropped,
+                next = ?p,
+                "[event] superseded while an apply runs"
+            );
+        }
+    }
+
+    /// Park `p` until the apply task is idle, dropping the job still waiting
+    /// there: `p` replaces it either way.
+    fn wait_for_applies(&mut self, p: Profile) {
+        if let Some(dropped) = self.applier.cancel_waiting() {
+            debug!(?dropped, next = ?p, "[event] superseded while an apply runs");
+        }
+        self.pending = Some(p);
+        self.pending_after_applies = true;
+    }
+
+    /// Apply the source's current profile before the loop starts. The same
+    /// steps as an event, minus debounce and dwell, and awaited in place.
+    /// With `--no-startup-sync` the profile is only recorded as `last`.
+    async fn sync_at_startup(&mut self) -> Result<()> {
+        let delay = self
+            .cli
+            .startup_delay_ms
+            .map_or(self.config.startup_delay, Duration::from_millis);
+        if !delay.is_zero() {
+            // The signal streams are already subscribed and buffer whatever
+            // changes meanwhile; the loop picks those up after this sync.
+            info!("[startup] waiting {delay:?} before the startup sync");
+            tokio::time::sleep(delay).await;
+        }
+        let Some(p) = read_startup_profile(&self.session, &self.config).await? else {
+            self.set_last(None, None).await;
+            return Ok(());
+        };
+        if self.cli.no_startup_sync {
+            // Counted as in effect, so only an actual change applies anything.
+            info!(profile = ?p, "[startup] --no-startup-sync: leaving the scheduler as it is");
+            self.set_last(Some(p), None).await;
+            return Ok(());
+        }
+        if self.still_in_standby() {
+            return Ok(());
+        }
+        match handle_profile(
+            &self.config,
+            self.session.power,
+            &self.session.degraded,
+            p,
+            &mut self.last,
+        )
         .await
-        .context("subscribe PropertiesChanged")?;
+        {
+            Handling::Unchanged => {}
+            Handling::LeftAlone => self.set_last(self.last, None).await,
+            Handling::Apply(mode) => {
+                // Normally skipped when already running, e.g. after a restart
+                // of just the daemon; re-applying would drop scheduling briefly.
+                let opts = ApplyOptions {
+                    force: self.opts.force || self.cli.force_startup_apply,
+                    ..self.opts
+                };
+                let result = apply_mode(&mode, &self.config.scx, &opts)
+                    .instrument(apply_span(p))
+                    .await
+                    .map_err(anyhow::Error::from);
+                self.on_applied(Finished {
+                    profile: p,
+                    generation: self.generation,
+                    mode,
+                    result,
+                })
+                .await;
+            }
+        }
+        Ok(())
+    }
+
+    /// SIGUSR1: log what the daemon believes is in effect next to what
+    /// scxctl reports right now.
+    async fn log_state(&self) {
+        let mode = self.last.and(self.applied.as_ref());
+        let scx = match scx_status(&self.config.scx).await {
+            Ok(status) => status.describe(),
+            Err(e) => format!("probe failed: {:#}", anyhow::Error::from(e)),
+        };
+        info!(
+            last = ?self.last,
+            mode = mode.map_or_else(|| "<none>".to_owned(), Mode::describe),
+            pending = ?self.pending,
+            in_flight = ?self.applier.running().map(|(profile, _)| profile),
+            power = ?self.session.power,
+            preset = self.config.active_preset.as_deref().unwrap_or("<none>"),
+            scx,
+            "[state] SIGUSR1 received"
+        );
+    }
+
+    /// In standby, look for sched_ext again (or a reload that set
+    /// `missing_sched_ext: ignore`) and leave standby if it is there.
+    fn still_in_standby(&mut self) -> bool {
+        if !self.standby {
+            return false;
+        }
+        if self.config.missing_sched_ext != MissingSchedExt::Ignore && !sched_ext_available() {
+            return true;
+        }
+        info!("[standby] sched_ext support found; applying profiles again");
+        self.standby = false;
+        false
+    }
+
+    /// Within `min_switch_interval` of the last switch, park `p` as pending
+    /// until the interval is over and return true.
+    fn hold_for_dwell(&mut self, p: Profile) -> bool {
+        let Some(last_switch) = self.last_switch else {
+            return false;
+        };
+        let until = last_switch + self.config.min_switch_interval;
+        let now = Instant::now();
+        if now >= until {
+            return false;
+        }
+        info!(
+            profile = ?p,
+            remaining = ?(until - now),
+            "[dwell] last switch too recent; holding"
+        );
+        self.pending = Some(p);
+        self.deadline = until;
+        true
+    }
+
+    /// During a failure cooldown, park `p` as pending until it is over and
+    /// return true.
+    fn hold_for_cooldown(&mut self, p: Profile) -> bool {
+        let Some(until) = self.cooldown_until else {
+            return false;
+        };
+        let now = Instant::now();
+        // A reload may have turned the cooldown off meanwhile.
+        if now >= until || self.config.cooldown_after_failures == 0 {
+            info!("[cooldown] over; applying again");
+            self.cooldown_until = None;
+            return false;
+        }
+        debug!(
+            profile = ?p,
+            remaining = ?(until - now),
+            "[cooldown] ignoring profile change"
+        );
+        self.pending = Some(p);
+        self.deadline = until;
+        true
+    }
+
+    /// Count a failed apply of `profile`. From the `cooldown_after_failures`th
+    /// in a row on, each one starts a cooldown, after which the latest
+    /// requested profile (else `profile` again) is tried once more.
+    fn note_failure(&mut self, profile: Profile) {
+        self.failed_in_a_row += 1;
+        let after = self.config.cooldown_after_failures;
+        if after == 0 || self.failed_in_a_row < after {
+            return;
+        }
+        let cooldown = self.config.failure_cooldown;
+        warn!(
+            failures = self.failed_in_a_row,
+            ?cooldown,
+            "[cooldown] applies keep failing; backing off"
+        );
+        let until = Instant::now() + cooldown;
+        self.cooldown_until = Some(until);
+        // A queued apply would run straight away; hold it too.
+        let queued = self.applier.cancel_waiting();
+        self.pending = self.pending.or(queued).or(Some(profile));
+        self.deadline = self.deadline.max(until);
+    }
 
-    while let Some(signal) = stream.next().await {
-        let args = match signal.args() {
-            Ok(a) => a,
+    async fn on_applied(&mut self, done: Finished) {
+        let Finished {
+            profile,
+            generation,
+            mode,
+            result,
+        } = done;
+        let current = generation == self.generation;
+        let last = settled_last(profile, result.is_ok(), current);
+        self.history.record(
+            profile,
+            mode.sched_label(),
+            result.as_ref().err().map(|e| format!("{e:#}")),
+        );
+        match result {
             Err(e) => {
-                warn!("signal args decode failed: {e}");
-                continue;
+                // A failed apply may have left anything running: forget what
+                // was in effect, so the same event arriving again retries.
+                self.set_last(last, None).await;
+                self.metrics.record_apply_failure();
+                if !self.should_log_failure(profile, &mode.sched) {
+                    debug!("apply_mode error (repeat): {e:#}");
+                } else {
+                    error!("apply_mode error: {e:#}");
+                    if self.cli.notify_on_error {
+                        notifications::apply_failed(profile, &mode.sched, &e);
+                    }
+                }
+                self.note_failure(profile);
+            }
+            Ok(()) => {
+                if !current {
+                    // `last` stays unset, but the mode did start: publish and
+                    // persist it like any other.
+                    debug!(profile = ?profile, "[apply] finished, but superseded meanwhile");
+                }
+                self.failed_in_a_row = 0;
+                self.flush_failures();
+                self.metrics.record_switch(profile);
+                self.last_switch = Some(Instant::now());
+                if let Some(path) = &self.config.state_file {
+                    state_file::write(path, profile, &mode, self.config.active_preset.as_deref());
+                }
+                self.set_last(last, Some(&mode)).await;
+            }
+        }
+    }
+
+    /// First failure for a profile+sched, or the first once
+    /// `FAILURE_LOG_WINDOW` has passed: log it. Repeats inside the window
+    /// are counted and summarized when it closes or `flush_failures` runs.
+    fn should_log_failure(&mut self, profile: Profile, sched: &str) -> bool {
+        let key = (profile, sched.to_owned());
+        if let Some(log) = self.failures.get_mut(&key) {
+            if log.since.elapsed() < FAILURE_LOG_WINDOW {
+                log.suppressed += 1;
+                return false;
             }
+            log_suppressed_failures(&key, log);
+        }
+        self.failures.insert(
+            key,
+            FailureLog {
+                since: Instant::now(),
+                suppressed: 0,
+            },
+        );
+        true
+    }
+
+    /// Log the counts `should_log_failure` held back and start over; called
+    /// once an apply succeeds again, and at shutdown.
+    fn flush_failures(&mut self) {
+        for (key, log) in self.failures.drain() {
+            log_suppressed_failures(&key, &log);
+        }
+    }
+
+    /// Queue the source's current profile for `apply_pending`, skipping the
+    /// debounce. `phase` only tags the log lines.
+    async fn queue_active_profile(&mut self, phase: &str) {
+        match read_active_profile(&self.session, &self.config, phase).await {
+            Ok(Some(p)) => {
+                self.pending = Some(p);
+                self.deadline = Instant::now();
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("[{phase}] {e:#}");
+                self.set_last(None, None).await;
+            }
+        }
+    }
+
+    /// Set `next_poll` one `poll_interval` from now, or clear it when
+    /// polling is off.
+    fn schedule_poll(&mut self) {
+        let interval = self.config.poll_interval;
+        self.next_poll = (!interval.is_zero()).then(|| Instant::now() + interval);
+    }
+
+    /// Re-read the active profile and queue it if it isn't the one in
+    /// effect: a safety net for a subscription that stopped delivering
+    /// `PropertiesChanged` while the connection stayed up. Nothing is done
+    /// while a change is already being handled, or after a failed apply.
+    async fn poll_active_profile(&mut self) {
+        self.schedule_poll();
+        if self.pending.is_some() || self.applier.is_busy() {
+            return;
+        }
+        let Some(last) = self.last else {
+            return;
         };
+        let p = match self.session.source.active_profile().await {
+            Ok(name) => match self.config.resolve_profile(&name) {
+                Ok(p) => p,
+                Err(_) => return,
+            },
+            Err(e) => {
+                debug!("[poll] {e:#}");
+                return;
+            }
+        };
+        if p == last {
+            debug!(profile = ?p, "[poll] unchanged");
+            return;
+        }
+        warn!(
+            profile = ?p,
+            last = ?last,
+            "[poll] active profile changed without a signal; applying it"
+        );
+        self.pending = Some(p);
+        self.deadline = Instant::now();
+    }
 
-        if args.interface_name() != IFACE {
-            continue;
+    fn on_power_change(&mut self, power: PowerSource) {
+        if power == self.session.power {
+            return;
+        }
+        info!(?power, "[event] power source changed");
+        self.session.power = power;
+        self.generation += 1;
+        // Re-apply the current profile's mode for the new source.
+        self.pending = self.pending.or(self.last);
+        self.last = None;
+        self.deadline = Instant::now() + self.config.debounce;
+    }
+
+    /// logind reported a resume: the scheduler may have been unloaded while
+    /// asleep and the source may not announce its profile again, so read it
+    /// and apply its mode as a reload would.
+    async fn on_resume(&mut self) {
+        info!("[resume] resumed from suspend; re-reading the active profile");
+        self.generation += 1;
+        self.last = None;
+        self.queue_active_profile("resume").await;
+    }
+
+    fn on_degraded_change(&mut self, degraded: String) {
+        if degraded == self.session.degraded {
+            return;
+        }
+        info!(degraded = %degraded, "[event] PerformanceDegraded changed");
+        self.session.degraded = degraded;
+        // Only performance's mode depends on it.
+        let current = self.pending.or(self.last);
+        if current.map(|p| self.config.mapped_profile(p)) != Some(Profile::Performance) {
+            return;
         }
+        self.generation += 1;
+        self.pending = current;
+        self.last = None;
+        self.deadline = Instant::now() + self.config.debounce;
+    }
 
-        let changed: &HashMap<&str, Value> = args.changed_properties();
-        if let Some(val) = changed.get("ActiveProfile") {
-            match Value::try_from(val.clone()) {
-                Ok(Value::Str(s)) => {
-                    match Profile::from_str(s.as_str()) {
-                        Ok(p) => {
-                            if last == Some(p) {
-                                // duplicate; ignore
-                                continue;
-                            }
-                            info!(profile = ?p, "[event] ActiveProfile");
-                            match config.mode_for(p) {
-                                Some(mode) => {
-                                    if let Err(e) = apply_mode(mode) {
-                                        error!("apply_mode error: {e:#}");
-                                    } else {
-                                        last = Some(p);
-                                    }
-                                }
-                                None => warn!("no mode configured for profile {:?}", p),
-                            }
+    async fn on_reconnected(&mut self, session: Session) {
+        let previous_power = self.session.power;
+        let previous_degraded = std::mem::take(&mut self.session.degraded);
+        self.session = session;
+        self.session
+            .serve_status(&self.requests_tx, &self.history)
+            .await;
+        // A power-source flip (or degradation) while disconnected changes the mode too.
+        if self.session.power != previous_power || self.session.degraded != previous_degraded {
+            self.generation += 1;
+            self.last = None;
+        }
+        self.queue_active_profile("reconnect").await;
+    }
+
+    /// Re-read the config; on success re-apply the current profile even if it
+    /// is unchanged, since its mode may not be. On failure keep the old config.
+    async fn reload(&mut self) {
+        match load_checked_config(self.cli).await {
+            Ok(mut new_config) => {
+                // Same binary unless scxctl_path changed; not worth asking again.
+                if new_config.scx.bin == self.config.scx.bin {
+                    new_config.scx.version = self.config.scx.version;
+                } else if !self.opts.dry_run {
+                    new_config.scx.version = detect_scxctl_version(&new_config.scx).await;
+                }
+                if new_config.source != self.config.source
+                    || new_config.bus != self.config.bus
+                    || new_config.ppd_dbus != self.config.ppd_dbus
+                {
+                    // Nothing to keep from the old session; start over on the new source.
+                    match Session::connect(&new_config, Duration::ZERO).await {
+                        Ok(session) => {
+                            self.session = session;
+                            self.session
+                                .serve_status(&self.requests_tx, &self.history)
+                                .await;
+                        }
+                        Err(e) => {
+                            error!("[reload] keeping previous configuration: {e:#}");
+                            return;
+                        }
+                    }
+                }
+                if let Some(old) = &self.config.state_file
+                    && new_config.state_file.as_ref() != Some(old)
+                {
+                    state_file::remove(old);
+                }
+                self.config = new_config;
+                self.history.set_capacity(self.config.history_size);
+                check_available_profiles(&self.session, &self.config).await;
+                if let Some(selected) = &self.selected_preset {
+                    match self.config.select_preset(selected.as_deref()) {
+                        Ok(()) => {}
+                        Err(e) => {
+                            warn!("[preset] {e} after reload; using the configured preset");
+                            self.selected_preset = None;
                         }
-                        Err(e) => warn!("unknown profile in event: {e}"),
                     }
                 }
-                Ok(_) => warn!("unexpected variant for ActiveProfile"),
-                Err(e) => warn!("value decode failed: {e}"),
+                if self.config.watches_power_source() && self.session.upower.is_none() {
+                    self.session.watch_power_source().await;
+                }
+                if !self.config.performance_degraded.is_empty()
+                    && self.session.ppd_changes.is_none()
+                {
+                    self.session.watch_degraded().await;
+                }
+                if self.config.reapply_on_resume && self.session.logind.is_none() {
+                    self.session.watch_sleep(self.config.bus).await;
+                }
+                self.generation += 1;
+                self.last = None;
+                self.schedule_poll();
+                self.queue_active_profile("reload").await;
             }
+            Err(e) => error!("[reload] keeping previous configuration: {e:#}"),
         }
     }
 
-    Ok(())
-}
+    /// Switch presets and re-apply the current profile through the new one.
+    fn set_preset(&mut self, name: Option<String>) {
+        if self.config.active_preset == name {
+            return;
+        }
+        if let Err(e) = self.config.select_preset(name.as_deref()) {
+            warn!("[preset] {e}");
+            return;
+        }
+        info!(
+            preset = name.as_deref().unwrap_or("<none>"),
+            "[preset] selected"
+        );
+        self.selected_preset = Some(name);
+        self.generation += 1;
+        self.pending = self.pending.or(self.last);
+        self.last = None;
+        self.deadline = Instant::now();
+    }
 
-fn init_logging() {
-    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| "info".parse().unwrap());
-    tracing_subscriber::fmt().with_env_filter(filter).init();
+    /// Record `last` as in effect, with `mode` the one applied for it, and
+    /// mirror it on our D-Bus interface.
+    async fn set_last(&mut self, last: Option<Profile>, mode: Option<&Mode>) {
+        self.last = last;
+        self.applied = mode.cloned();
+        self.session.publish_status(last, mode, &self.config).await;
+    }
 }
 
-fn ensure_bin(bin: &str) -> Result<()> {
-    which::which(bin).with_context(|| format!("required binary not found in PATH: {bin}"))?;
-    Ok(())
+/// What `last` becomes once an apply for `profile` finishes: `profile` only
+/// if it succeeded and nothing changed its mode meanwhile (`current`). Else
+/// `None`, so `handle_profile` doesn't treat a repeat of the event as a
+/// duplicate and applies again.
+fn settled_last(profile: Profile, succeeded: bool, current: bool) -> Option<Profile> {
+    (succeeded && current).then_some(profile)
 }
 
-fn scxctl<I, S>(args: I) -> Result<Output>
-where
-    I: IntoIterator<Item = S>,
-    S: Into<OsString> + AsRef<std::ffi::OsStr>,
-{
-    Command::new("scxctl")
-        .args(args)
-        .output()
-        .with_context(|| "failed to exec scxctl")
+/// The summary line for failures `Daemon::should_log_failure` held back.
+fn log_suppressed_failures((profile, sched): &(Profile, String), log: &FailureLog) {
+    if log.suppressed > 0 {
+        error!(
+            "apply_mode for {} ({sched}) failed {} times in the last {}s",
+            profile.as_config_key(),
+            log.suppressed + 1,
+            log.since.elapsed().as_secs()
+        );
+    }
 }
 
-fn scx_running() -> Result<bool> {
-    let out = scxctl(["get"])?;
-    // If scxctl itself errors, treat as not running but log.
-    if !out.status.success() {
-        warn!(
-            "scxctl get exit={} stderr={}",
-            out.status.code().unwrap_or(-1),
-            String::from_utf8_lossy(&out.stderr).trim()
-        );
+/// Watch the directories holding `paths` rather than the files themselves,
+/// so a save that renames a new file over the old one is still seen.
+fn watch_config(paths: &[PathBuf]) -> Result<(RecommendedWatcher, mpsc::UnboundedReceiver<()>)> {
+    let mut file_names = Vec::new();
+    let mut dirs = Vec::new();
+    for path in paths {
+        let file_name = path
+            .file_name()
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| anyhow!("config path has no file name: {}", path.display()))?;
+        file_names.push(file_name);
+        dirs.push(match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        });
     }
-    let stdout = String::from_utf8_lossy(&out.stdout);
-    Ok(!stdout.to_lowercase().contains("no scx scheduler running"))
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        match event {
+            // Our own reads show up as access events; ignore those.
+            Ok(event)
+                if !event.kind.is_access()
+                    && event.paths.iter().any(|p| {
+                        p.file_name()
+                            .is_some_and(|name| file_names.iter().any(|f| f == name))
+                    }) =>
+            {
+                let _ = tx.send(());
+            }
+            Ok(_) => {}
+            Err(e) => warn!("[watch] {e}"),
+        }
+    })
+    .context("create config watcher")?;
+    for dir in dirs {
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("watch {}", dir.display()))?;
+    }
+    Ok((watcher, rx))
 }
 
-fn apply_mode(mode: &Mode) -> Result<()> {
-    let running = scx_running().context("probe scx running")?;
-    let subcmd = if running { "switch" } else { "start" };
-    info!(
-        subcmd,
-        sched = %mode.sched,
-        args = %mode.args,
-        "[apply]"
-    );
+/// Resolves on the next config file event; never while not watching.
+async fn config_change(
+    watch: &mut Option<(RecommendedWatcher, mpsc::UnboundedReceiver<()>)>,
+) -> Option<()> {
+    match watch {
+        Some((_, rx)) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
 
-    // Keep the entire args payload as *one* argument: --args=".."
-    let full = format!("--args={}", mode.args);
-    let out = scxctl([subcmd, "--sched", mode.sched.as_str(), &full])?;
-
-    let code = out.status.code().unwrap_or(-1);
-    let stdout = String::from_utf8_lossy(&out.stdout).trim().to_owned();
-    let stderr = String::from_utf8_lossy(&out.stderr).trim().to_owned();
-
-    if !out.status.success() {
-        Err(anyhow!(
-            "scxctl {} failed (exit={}): {}",
-            subcmd,
-            code,
-            stderr
-        ))
-    } else {
-        if !stdout.is_empty() {
-            info!("[scxctl] {stdout}");
+/// Resolves on the next watchdog tick, or never if the watchdog is disabled.
+async fn watchdog_tick(watchdog: &mut Option<tokio::time::Interval>) {
+    match watchdog {
+        Some(interval) => {
+            interval.tick().await;
         }
-        Ok(())
+        None => std::future::pending().await,
     }
 }
 
-fn load_config() -> Result<Config> {
-    let path = ensure_config_file().context("locate configuration file")?;
-    let contents = fs::read_to_string(&path)
-        .with_context(|| format!("read configuration {}", path.display()))?;
+fn ping_watchdog() {
+    if let Err(e) = sd_notify::notify(&[sd_notify::NotifyState::Watchdog]) {
+        warn!("sd_notify WATCHDOG failed: {e}");
+    }
+}
 
-    let raw: RawConfig =
-        serde_yaml::from_str(&contents).with_context(|| format!("parse {}", path.display()))?;
+/// Wraps one `apply_mode` so its log lines, `[apply] finished` with its
+/// `elapsed_ms` included, carry the profile and the subcommand used.
+fn apply_span(profile: Profile) -> tracing::Span {
+    info_span!(
+        "apply",
+        profile = profile.as_config_key(),
+        subcmd = tracing::field::Empty
+    )
+}
 
-    let mut modes = HashMap::new();
-    for (key, definition) in raw.modes {
-        let profile = Profile::from_str(key.as_str())
-            .with_context(|| format!("unknown profile '{key}' in {}", path.display()))?;
-        if modes.insert(profile, Mode::from(definition)).is_some() {
-            return Err(anyhow!(
-                "duplicate configuration for profile '{}' in {}",
-                profile.as_config_key(),
-                path.display()
-            ));
-        }
+/// What `p` becoming active calls for; see `handle_profile`.
+#[derive(Debug)]
+enum Handling {
+    /// `p` is already the profile in effect.
+    Unchanged,
+    /// Nothing to apply (disabled or unconfigured); `last` is now `p`.
+    LeftAlone,
+    /// Apply this mode; `last` becomes `p` once that succeeds.
+    Apply(Mode),
+}
+
+/// `config.choose_mode`, except that `performance_degraded` stands in for
+/// performance's own mode while PPD reports it degraded (`degraded`, ""
+/// when not).
+async fn choose_mode<'c>(
+    config: &'c Config,
+    p: Profile,
+    power: PowerSource,
+    degraded: &str,
+) -> Option<&'c Mode> {
+    if p == Profile::Performance
+        && let Some(set) = config.degraded_mode_set(degraded)
+    {
+        info!(
+            "[degraded] performance is degraded ({degraded}); using its performance_degraded mode"
+        );
+        return set.choose(power).await;
     }
+    config.choose_mode(p, power).await
+}
 
-    for profile in Profile::all() {
-        if !modes.contains_key(&profile) {
-            return Err(anyhow!(
-                "configuration {} missing profile '{}'",
-                path.display(),
-                profile.as_config_key()
-            ));
+/// The shared first step for a profile read at startup or reported by an
+/// event: skip it if it is `last` already, otherwise pick its mode, running
+/// any selector. When there is nothing to apply `last` is updated here; it
+/// is the callers' job only after a successful apply.
+async fn handle_profile(
+    config: &Config,
+    power: PowerSource,
+    degraded: &str,
+    p: Profile,
+    last: &mut Option<Profile>,
+) -> Handling {
+    if *last == Some(p) {
+        return Handling::Unchanged;
+    }
+    // `last` stays the reported profile; only the lookup is redirected.
+    let lookup = config.mapped_profile(p);
+    if lookup != p {
+        info!(
+            "[profile-map] using the mode for {} while {} is active",
+            lookup.as_config_key(),
+            p.as_config_key()
+        );
+    }
+    match choose_mode(config, lookup, power, degraded).await {
+        Some(mode) => Handling::Apply(mode.for_profile(p)),
+        None => {
+            log_unchanged(config, lookup);
+            *last = Some(p);
+            Handling::LeftAlone
         }
     }
+}
+
+/// Why nothing is applied for `p`: an `enabled: false` entry (possibly
+/// picked by a selector) or no entry.
+fn log_unchanged(config: &Config, p: Profile) {
+    if config.mode_set_for(p).is_some() {
+        info!(
+            "profile {} disabled, leaving scheduler unchanged",
+            p.as_config_key()
+        );
+    } else {
+        info!(
+            "no mode configured for profile {:?}; leaving scheduler unchanged",
+            p
+        );
+    }
+}
+
+/// Log to stderr, or with `--log-file` to a file rotated daily. The returned
+/// guard flushes the file writer when dropped, so keep it until exit.
+fn init_logging(log_file: Option<&Path>) -> Result<Option<WorkerGuard>> {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "info".parse().unwrap());
+    if let Some(path) = log_file {
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("--log-file {} names no file", path.display()))?;
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("create log directory {}", dir.display()))?;
+        let appender = RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix(name.to_string_lossy())
+            .max_log_files(LOG_FILES_KEPT)
+            .build(dir)
+            .with_context(|| format!("open log file {}", path.display()))?;
+        // Written from a background thread, so a slow disk can't stall the
+        // event loop; lines are dropped rather than block if it falls behind.
+        let (writer, guard) = tracing_appender::non_blocking(appender);
+        tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_writer(writer)
+            .with_ansi(false)
+            .init();
+        return Ok(Some(guard));
+    }
+    // Colors and the compact format only for someone watching a terminal;
+    // piped output and the journal get plain lines. A non-empty NO_COLOR
+    // (https://no-color.org) turns colors off there too.
+    let interactive = std::io::stderr().is_terminal();
+    let ansi = interactive && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty());
+    // stderr, so `migrate` and `status` output on stdout stays clean.
+    let fmt = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(ansi);
+    if interactive {
+        fmt.compact().init();
+    } else {
+        fmt.init();
+    }
+    Ok(None)
+}
 
-    info!(config = %path.display(), "loaded configuration");
+async fn load_checked_config(cli: &Cli) -> Result<Config> {
+    let mut config = load_cli_config(cli)?;
+    config.profile_map = cli.profile_map.iter().copied().collect();
+    check_schedulers(&config, cli.strict).await?;
+    Ok(config)
+}
+
+/// `--config`, the first config found, or with `--merge-configs` all of them.
+fn load_cli_config(cli: &Cli) -> Result<Config> {
+    let config = if cli.merge_configs {
+        load_merged_config()?
+    } else {
+        load_config(cli.config.as_deref())?
+    };
+    Ok(config)
+}
 
-    Ok(Config { modes })
+/// `--profile-map FROM=TO`.
+fn parse_profile_mapping(s: &str) -> Result<(Profile, Profile)> {
+    let (from, to) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected FROM=TO, e.g. balanced=performance"))?;
+    Ok((from.parse()?, to.parse()?))
 }
 
-fn ensure_config_file() -> Result<PathBuf> {
-    let candidates = config_search_paths();
-    for candidate in &candidates {
-        if candidate.exists() {
-            return Ok(candidate.clone());
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::ProfileSource;
+    use futures_util::future::BoxFuture;
+
+    fn test_config(name: &str) -> Config {
+        config_from(
+            name,
+            "require_all_profiles: false\n\
+             modes:\n  \
+               performance: { sched: flash, args: [] }\n  \
+               balanced: { enabled: false }\n",
+        )
     }
 
-    let searched = candidates
-        .iter()
-        .map(|p| p.display().to_string())
-        .collect::<Vec<_>>()
-        .join(", ");
+    fn config_from(name: &str, yaml: &str) -> Config {
+        let path =
+            std::env::temp_dir().join(format!("scx-power-sync-{name}-{}.yaml", std::process::id()));
+        std::fs::write(&path, yaml).unwrap();
+        let config = load_config(Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
 
-    Err(anyhow!(
-        "configuration file not found; looked in: {}",
-        if searched.is_empty() {
-            "<none>".to_string()
-        } else {
-            searched
+    /// Always reports `performance`; the tests feed profiles in directly.
+    struct FixedSource;
+
+    impl ProfileSource for FixedSource {
+        fn active_profile(&self) -> BoxFuture<'_, Result<String>> {
+            Box::pin(async { Ok("performance".to_owned()) })
         }
-    ))
-}
 
-fn config_search_paths() -> Vec<PathBuf> {
-    let mut paths = Vec::new();
+        fn available_profiles(&self) -> BoxFuture<'_, Result<Vec<String>>> {
+            Box::pin(async { Ok(Vec::new()) })
+        }
 
-    if let Ok(home) = env::var("HOME") {
-        let home_config = Path::new(&home)
-            .join(".config")
-            .join(CONFIG_DIR_NAME)
-            .join(CONFIG_FILE_NAME);
-        paths.push(home_config);
+        fn next_change(&mut self) -> BoxFuture<'_, Option<String>> {
+            Box::pin(std::future::pending())
+        }
     }
 
-    if let Ok(value) = env::var("XDG_CONFIG_HOME") {
-        if !value.is_empty() {
-            let xdg_path = PathBuf::from(&value)
-                .join(CONFIG_DIR_NAME)
-                .join(CONFIG_FILE_NAME);
-            if !paths.contains(&xdg_path) {
-                paths.push(xdg_path);
+    /// A `Daemon` on one end of a peer-to-peer connection, so no bus is
+    /// needed; the other end is returned to be kept alive alongside it.
+    async fn test_daemon(cli: &Cli, config: Config) -> (Daemon<'_>, Connection) {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let (conn, peer) = tokio::join!(
+            zbus::connection::Builder::unix_stream(ours)
+                .server(zbus::Guid::generate())
+                .unwrap()
+                .p2p()
+                .build(),
+            zbus::connection::Builder::unix_stream(theirs).p2p().build(),
+        );
+        let session = Session::detached(conn.unwrap(), Box::new(FixedSource));
+        let daemon = Daemon::new(
+            cli,
+            config,
+            ApplyOptions::default(),
+            session,
+            Arc::default(),
+            false,
+        );
+        // The name can't be owned without a bus; the interface is still served.
+        daemon
+            .session
+            .serve_status(&daemon.requests_tx, &daemon.history)
+            .await;
+        (daemon, peer.unwrap())
+    }
+
+    /// `handle_profile` against what `daemon` has in effect, then what
+    /// `Daemon::apply_pending` does with the outcome, with `result` standing
+    /// in for the apply.
+    async fn settle_with(daemon: &mut Daemon<'_>, p: Profile, result: Result<()>) -> &'static str {
+        let handling = handle_profile(
+            &daemon.config,
+            daemon.session.power,
+            &daemon.session.degraded,
+            p,
+            &mut daemon.last,
+        )
+        .await;
+        match handling {
+            Handling::Unchanged => "unchanged",
+            Handling::LeftAlone => {
+                daemon.set_last(daemon.last, None).await;
+                "left alone"
+            }
+            Handling::Apply(mode) => {
+                assert_eq!(mode.sched, "flash");
+                daemon
+                    .on_applied(Finished {
+                        profile: p,
+                        generation: daemon.generation,
+                        mode,
+                        result,
+                    })
+                    .await;
+                "applied"
             }
         }
     }
 
-    if let Ok(raw) = env::var("XDG_CONFIG_DIRS") {
-        for entry in raw.split(':').filter(|s| !s.is_empty()) {
-            let path = PathBuf::from(entry)
-                .join(CONFIG_DIR_NAME)
-                .join(CONFIG_FILE_NAME);
-            if !paths.contains(&path) {
-                paths.push(path);
-            }
+    async fn settle(daemon: &mut Daemon<'_>, p: Profile) -> &'static str {
+        settle_with(daemon, p, Ok(())).await
+    }
+
+    #[test]
+    fn doctor_hints_line_up_under_the_detail() {
+        assert_eq!(
+            check_line("ok", "scxctl", "/usr/bin/scxctl", None),
+            "[ ok ] scxctl           /usr/bin/scxctl"
+        );
+        let line = check_line("FAIL", "sched_ext", "missing", Some("boot another kernel"));
+        let (first, hint) = line.split_once('\n').unwrap();
+        assert_eq!(first, "[FAIL] sched_ext        missing");
+        assert_eq!(hint.find("hint:"), first.find("missing"));
+    }
+
+    #[tokio::test]
+    async fn test_modes_needs_confirmation() {
+        let config = test_config("test-modes");
+        let opts = ApplyOptions::default();
+        let err = run_test_modes(&config, &opts, Duration::ZERO, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().ends_with("pass --yes to go ahead"), "{err}");
+        let opts = ApplyOptions {
+            dry_run: true,
+            ..opts
+        };
+        let err = run_test_modes(&config, &opts, Duration::ZERO, true)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("--dry-run"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn startup_and_event_paths_update_last_alike() {
+        let cli = Cli::parse_from(["scx-power-sync-dbus"]);
+        let (mut daemon, _peer) = test_daemon(&cli, test_config("alike")).await;
+        for p in Profile::all() {
+            // Startup: nothing in effect yet. Event: some other profile is.
+            daemon.last = None;
+            let startup = settle(&mut daemon, p).await;
+            assert_eq!(daemon.last, Some(p), "{p:?}");
+            let status = daemon.session.published().await;
+            assert_eq!(status.0, p.as_config_key(), "{p:?}");
+            // Seeing the profile again is a no-op.
+            assert_eq!(settle(&mut daemon, p).await, "unchanged");
+
+            daemon.last = Profile::all().into_iter().find(|&o| o != p);
+            let event = settle(&mut daemon, p).await;
+            assert_eq!(startup, event, "{p:?}");
+            assert_eq!(daemon.last, Some(p), "{p:?}");
+            assert_eq!(daemon.session.published().await, status, "{p:?}");
+            assert_eq!(settle(&mut daemon, p).await, "unchanged");
         }
-    } else {
-        let path = PathBuf::from("/etc/xdg")
-            .join(CONFIG_DIR_NAME)
-            .join(CONFIG_FILE_NAME);
-        if !paths.contains(&path) {
-            paths.push(path);
+
+        // An apply that a reload overtook leaves `last` unset, and says so.
+        let p = Profile::Performance;
+        assert_eq!(settle(&mut daemon, p).await, "applied");
+        let mode = daemon.applied.clone().unwrap();
+        daemon.generation += 1;
+        daemon
+            .on_applied(Finished {
+                profile: p,
+                generation: daemon.generation - 1,
+                mode,
+                result: Ok(()),
+            })
+            .await;
+        assert_eq!(daemon.last, None);
+        assert_eq!(
+            daemon.session.published().await,
+            (String::new(), "flash".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn apply_does_not_touch_last() {
+        let config = test_config("apply");
+        let mut last = Some(Profile::Balanced);
+        let handling = handle_profile(
+            &config,
+            PowerSource::Ac,
+            "",
+            Profile::Performance,
+            &mut last,
+        )
+        .await;
+        assert!(matches!(handling, Handling::Apply(_)), "{handling:?}");
+        assert_eq!(last, Some(Profile::Balanced));
+
+        for p in [Profile::Balanced, Profile::PowerSaver] {
+            let mut last = None;
+            let handling = handle_profile(&config, PowerSource::Ac, "", p, &mut last).await;
+            assert!(matches!(handling, Handling::LeftAlone), "{handling:?}");
+            assert_eq!(last, Some(p));
         }
     }
 
-    let fallback = PathBuf::from("/etc")
-        .join(CONFIG_DIR_NAME)
-        .join(CONFIG_FILE_NAME);
-    if !paths.contains(&fallback) {
-        paths.push(fallback);
+    #[tokio::test]
+    async fn failed_apply_is_retried_on_a_duplicate_event() {
+        let cli = Cli::parse_from(["scx-power-sync-dbus"]);
+        let (mut daemon, _peer) = test_daemon(&cli, test_config("retry")).await;
+        daemon.last = Some(Profile::Balanced);
+        let p = Profile::Performance;
+        let failed = Err(anyhow!("scxctl start failed (exit=1): boom"));
+        assert_eq!(settle_with(&mut daemon, p, failed).await, "applied");
+        assert_eq!(daemon.last, None);
+        // The same profile reported again is not a duplicate after a failure.
+        assert_eq!(settle(&mut daemon, p).await, "applied");
+        assert_eq!(daemon.last, Some(p));
+        assert_eq!(settle(&mut daemon, p).await, "unchanged");
+    }
+
+    /// Records every call; the first `start` blocks until `release`.
+    #[derive(Debug, Default)]
+    struct SlowStart {
+        calls: std::sync::Mutex<Vec<String>>,
+        blocked: std::sync::atomic::AtomicBool,
+        started: tokio::sync::Notify,
+        release: tokio::sync::Notify,
+    }
+
+    impl scx_power_sync_dbus::ScxRunner for SlowStart {
+        fn run<'a>(
+            &'a self,
+            _: &'a Path,
+            args: &'a [std::ffi::OsString],
+            _: &'a [(String, Option<String>)],
+        ) -> BoxFuture<'a, std::io::Result<std::process::Output>> {
+            use std::os::unix::process::ExitStatusExt;
+            use std::sync::atomic::Ordering;
+            let call = args
+                .iter()
+                .map(|a| a.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ");
+            let block = call.starts_with("start") && !self.blocked.swap(true, Ordering::SeqCst);
+            self.calls.lock().unwrap().push(call);
+            Box::pin(async move {
+                if block {
+                    self.started.notify_one();
+                    self.release.notified().await;
+                }
+                Ok(std::process::Output {
+                    status: std::process::ExitStatus::from_raw(0),
+                    stdout: b"no scx scheduler running".to_vec(),
+                    stderr: Vec::new(),
+                })
+            })
+        }
     }
 
-    paths
+    #[tokio::test]
+    async fn profile_superseded_during_an_apply_is_skipped() {
+        let cli = Cli::parse_from(["scx-power-sync-dbus"]);
+        let config = config_from(
+            "superseded",
+            "modes:\n  \
+               performance: { sched: flash, args: [] }\n  \
+               balanced: { sched: lavd, args: [] }\n  \
+               power-saver: { sched: bpfland, args: [] }\n",
+        );
+        let (mut daemon, _peer) = test_daemon(&cli, config).await;
+        let scx = Arc::new(SlowStart::default());
+        daemon.config.scx.runner = scx.clone();
+
+        daemon.pending = Some(Profile::Performance);
+        daemon.apply_pending().await;
+        scx.started.notified().await;
+        // Both arrive while flash is still starting; only the newer one runs.
+        for p in [Profile::Balanced, Profile::PowerSaver] {
+            daemon.pending = Some(p);
+            daemon.apply_pending().await;
+        }
+        scx.release.notify_one();
+        while daemon.applier.is_busy() {
+            let done = daemon.applier.finished().await;
+            daemon.on_applied(done).await;
+        }
+
+        assert_eq!(daemon.last, Some(Profile::PowerSaver));
+        let starts: Vec<String> = scx
+            .calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| call.starts_with("start"))
+            .cloned()
+            .collect();
+        assert_eq!(starts.len(), 2, "{starts:?}");
+        assert!(starts[0].contains("flash"), "{starts:?}");
+        assert!(starts[1].contains("bpfland"), "{starts:?}");
+    }
 }
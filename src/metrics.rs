@@ -0,0 +1,102 @@
+//! Prometheus counters, served over plain HTTP when `--metrics-addr` is given.
+
+use anyhow::{Context, Result};
+use scx_power_sync_dbus::Profile;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Indexed like `Profile::all()`.
+    switches: [AtomicU64; 3],
+    apply_failures: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_switch(&self, profile: Profile) {
+        let idx = Profile::all()
+            .iter()
+            .position(|p| *p == profile)
+            .expect("Profile::all lists every profile");
+        self.switches[idx].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_apply_failure(&self) {
+        self.apply_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP scx_power_sync_switches_total Modes applied, by power profile.\n");
+        out.push_str("# TYPE scx_power_sync_switches_total counter\n");
+        for (profile, count) in Profile::all().iter().zip(&self.switches) {
+            let _ = writeln!(
+                out,
+                "scx_power_sync_switches_total{{profile=\"{}\"}} {}",
+                profile.as_config_key(),
+                count.load(Ordering::Relaxed)
+            );
+        }
+        out.push_str(
+            "# HELP scx_power_sync_apply_failures_total Failed attempts to apply a mode.\n",
+        );
+        out.push_str("# TYPE scx_power_sync_apply_failures_total counter\n");
+        let _ = writeln!(
+            out,
+            "scx_power_sync_apply_failures_total {}",
+            self.apply_failures.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+/// Bind `addr` and answer `GET /metrics` in a background task.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("bind metrics endpoint {addr}"))?;
+    info!(%addr, "[metrics] serving /metrics");
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let metrics = metrics.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = respond(stream, &metrics).await {
+                            debug!(%peer, "[metrics] {e:#}");
+                        }
+                    });
+                }
+                Err(e) => warn!("[metrics] accept failed: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// One request per connection; only the request line is looked at.
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf).await.context("read request")?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "not found\n".to_owned()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .context("write response")?;
+    stream.shutdown().await.context("close connection")
+}
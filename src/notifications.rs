@@ -0,0 +1,71 @@
+//! Desktop notifications for failed applies, enabled by `--notify-on-error`.
+
+use anyhow::{Context, Result};
+use scx_power_sync_dbus::Profile;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::debug;
+use zbus::{Connection, Proxy};
+use zvariant::Value;
+
+const DEST: &str = "org.freedesktop.Notifications";
+const PATH: &str = "/org/freedesktop/Notifications";
+const IFACE: &str = "org.freedesktop.Notifications";
+/// Don't hold up anything on a notification server that never answers.
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+/// Keep the body to what fits in a notification bubble.
+const TAIL_LINES: usize = 4;
+const TAIL_CHARS: usize = 400;
+
+/// Report a failed apply in the background. Without a session bus or a
+/// notification server this is only logged at debug level.
+pub fn apply_failed(profile: Profile, sched: &str, error: &anyhow::Error) {
+    let summary = format!("scx: switching to {} failed", profile.as_config_key());
+    let body = format!("scheduler: {sched}\n{}", tail(&format!("{error:#}")));
+    tokio::spawn(async move {
+        match tokio::time::timeout(SEND_TIMEOUT, send(&summary, &body)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!("[notify] not sent: {e:#}"),
+            Err(_) => debug!("[notify] not sent: timed out after {SEND_TIMEOUT:?}"),
+        }
+    });
+}
+
+async fn send(summary: &str, body: &str) -> Result<()> {
+    let conn = Connection::session()
+        .await
+        .context("connect session D-Bus")?;
+    let proxy = Proxy::new(&conn, DEST, PATH, IFACE)
+        .await
+        .context("create Notifications proxy")?;
+    let actions: Vec<&str> = Vec::new();
+    let hints: HashMap<&str, Value> = HashMap::new();
+    let _id: u32 = proxy
+        .call(
+            "Notify",
+            &(
+                "scx-power-sync-dbus",
+                0u32,
+                "dialog-error",
+                summary,
+                body,
+                actions,
+                hints,
+                -1i32,
+            ),
+        )
+        .await
+        .context("Notify")?;
+    Ok(())
+}
+
+/// Last few lines of `text`, further capped in length; scxctl's stderr ends
+/// up at the end of the error chain.
+fn tail(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let tail = lines[lines.len().saturating_sub(TAIL_LINES)..].join("\n");
+    match tail.char_indices().rev().nth(TAIL_CHARS - 1) {
+        Some((start, _)) => format!("…{}", &tail[start..]),
+        None => tail,
+    }
+}
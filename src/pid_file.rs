@@ -0,0 +1,90 @@
+//! The daemon's PID, so a second instance refuses to start and `reload` can
+//! find the first one without D-Bus.
+
+use anyhow::{Context, Result, anyhow};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+const FILE_NAME: &str = "scx-power-sync-dbus.pid";
+
+/// `--pidfile`, else `$XDG_RUNTIME_DIR/scx-power-sync-dbus.pid`; `None` when
+/// neither is set (e.g. a system service without a runtime directory).
+pub fn path(flag: Option<&Path>) -> Option<PathBuf> {
+    flag.map(Path::to_owned).or_else(|| {
+        env::var_os("XDG_RUNTIME_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| PathBuf::from(dir).join(FILE_NAME))
+    })
+}
+
+/// Holds the PID file for as long as it lives; dropping it removes the file.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Create `path` holding our PID. Fails if it names another live
+    /// instance; a file left by one that died is replaced.
+    pub fn acquire(path: &Path) -> Result<Self> {
+        if let Some(pid) = running_pid(path) {
+            return Err(anyhow!(
+                "another instance is already running (pid {pid}, per {})",
+                path.display()
+            ));
+        }
+        match fs::remove_file(path) {
+            Ok(()) => info!(path = %path.display(), "[pidfile] removed stale PID file"),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("remove {}", path.display())),
+        }
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        }
+        // create_new: if another instance got here between the check and now,
+        // one of us loses instead of both overwriting the file.
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("create PID file {}", path.display()))?;
+        writeln!(file, "{}", std::process::id())
+            .with_context(|| format!("write PID file {}", path.display()))?;
+        Ok(PidFile {
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Leave it alone if it's no longer ours.
+        if read_pid(&self.path) != Some(std::process::id()) {
+            return;
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("[pidfile] remove {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// The PID in `path`, if it belongs to a live instance of this program other
+/// than us. A recycled PID now used by something else doesn't count.
+pub fn running_pid(path: &Path) -> Option<u32> {
+    let pid = read_pid(path)?;
+    if pid == std::process::id() {
+        return None;
+    }
+    let comm = |pid: &str| fs::read_to_string(format!("/proc/{pid}/comm")).ok();
+    match (comm(&pid.to_string()), comm("self")) {
+        (Some(theirs), Some(ours)) if theirs == ours => Some(pid),
+        _ => None,
+    }
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
@@ -0,0 +1,142 @@
+//! What the shared logic needs from a runtime: sleeping, time limits and
+//! child processes. Tokio with the `async` feature (which wins if both are
+//! on, so enabling `blocking` somewhere in a build can't stall the tokio
+//! daemon's executor); plain std with only `blocking`, where these futures
+//! finish on their first poll and `block_on` is all the executor there is.
+
+use std::future::Future;
+use std::io;
+use std::process::{Command, Output};
+use std::time::Duration;
+
+/// A time limit ran out.
+#[derive(Debug)]
+pub(crate) struct Elapsed;
+
+#[cfg(feature = "async")]
+pub(crate) async fn sleep(d: Duration) {
+    tokio::time::sleep(d).await;
+}
+
+/// `fut`, unless `limit` passes first; it's dropped then.
+#[cfg(feature = "async")]
+pub(crate) async fn timeout<F: Future>(limit: Duration, fut: F) -> Result<F::Output, Elapsed> {
+    tokio::time::timeout(limit, fut).await.map_err(|_| Elapsed)
+}
+
+/// Run `cmd` to completion, capturing stdout and stderr.
+#[cfg(feature = "async")]
+pub(crate) async fn output(cmd: Command) -> io::Result<Output> {
+    tokio::process::Command::from(cmd).output().await
+}
+
+/// `output`, killing the child once it has run for `limit`.
+#[cfg(feature = "async")]
+pub(crate) async fn output_within(
+    cmd: Command,
+    limit: Duration,
+) -> Result<io::Result<Output>, Elapsed> {
+    let mut cmd = tokio::process::Command::from(cmd);
+    timeout(limit, cmd.kill_on_drop(true).output()).await
+}
+
+#[cfg(not(feature = "async"))]
+pub(crate) async fn sleep(d: Duration) {
+    std::thread::sleep(d);
+}
+
+/// Nothing here can be interrupted, so `fut` just runs; `output_within`
+/// enforces its own limit instead.
+#[cfg(not(feature = "async"))]
+pub(crate) async fn timeout<F: Future>(_limit: Duration, fut: F) -> Result<F::Output, Elapsed> {
+    Ok(fut.await)
+}
+
+/// How often a limited child is checked for having exited.
+#[cfg(not(feature = "async"))]
+const CHILD_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Run `cmd` to completion, capturing stdout and stderr.
+#[cfg(not(feature = "async"))]
+pub(crate) async fn output(mut cmd: Command) -> io::Result<Output> {
+    cmd.output()
+}
+
+/// `output`, killing the child once it has run for `limit`.
+#[cfg(not(feature = "async"))]
+pub(crate) async fn output_within(
+    mut cmd: Command,
+    limit: Duration,
+) -> Result<io::Result<Output>, Elapsed> {
+    use std::io::Read;
+    use std::process::Stdio;
+    use std::thread;
+    use std::time::Instant;
+
+    let mut child = match cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+        Ok(child) => child,
+        Err(e) => return Ok(Err(e)),
+    };
+    // Drained on their own threads so a chatty child can't fill a pipe and
+    // stall while we wait for it.
+    fn drain(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buf);
+            }
+            buf
+        })
+    }
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = Instant::now() + limit;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Elapsed);
+            }
+            Ok(None) => thread::sleep(CHILD_POLL_INTERVAL),
+            Err(e) => return Ok(Err(e)),
+        }
+    };
+    Ok(Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    }))
+}
+
+/// Drive `fut` on the calling thread. Everything this crate awaits finishes
+/// without yielding in the `blocking` build; a custom `ScxRunner` that does
+/// yield is polled again after a short sleep, as there is nothing to wake us.
+#[cfg(all(feature = "blocking", not(feature = "async")))]
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    let mut fut = pin!(fut);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+            return out;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Drive `fut` to completion on a current-thread tokio runtime of its own;
+/// with both features on, the library's futures need one. Must not be
+/// called from inside a tokio runtime.
+#[cfg(all(feature = "blocking", feature = "async"))]
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build a tokio runtime")
+        .block_on(fut)
+}
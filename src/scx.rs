@@ -0,0 +1,1048 @@
+//! Running scxctl: applying a mode (with its hooks and EPP), reading what
+//! is loaded, and checking schedulers and their flags.
+
+use crate::error::chain;
+use crate::rt;
+use crate::{ApplyError, ArgsStyle, Config, ConfigError, Mode, ModeAction, ScxSubcommand};
+use futures_util::future::BoxFuture;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+const CPU_SYSFS: &str = "/sys/devices/system/cpu";
+const SCHED_EXT_SYSFS: &str = "/sys/kernel/sched_ext";
+const VERIFY_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Whether the running kernel was built with sched_ext, judged by its sysfs
+/// directory.
+pub fn sched_ext_available() -> bool {
+    Path::new(SCHED_EXT_SYSFS).is_dir()
+}
+
+/// How scxctl gets invoked, independent of which mode is being applied.
+#[derive(Debug, Clone)]
+pub struct ScxSettings {
+    /// `scxctl` binary: a bare name (PATH lookup) or an absolute path.
+    pub bin: PathBuf,
+    /// Passed ahead of the subcommand on every call, e.g. `--verbose`.
+    pub global_args: Vec<String>,
+    /// Extra attempts after a non-zero start/switch exit.
+    pub retries: u32,
+    /// Delay before the first retry; doubled after each one.
+    pub retry_delay: Duration,
+    /// Per-invocation limit; scxctl is killed once it runs longer.
+    pub timeout: Duration,
+    /// After a successful start/switch, poll `scxctl get` for this long until
+    /// it names the requested scheduler; `None` skips the check.
+    pub verify: Option<Duration>,
+    /// What actually executes `bin`: `ProcessRunner` outside of tests.
+    pub runner: Arc<dyn ScxRunner>,
+    /// What `scxctl --version` reported at startup (see
+    /// `detect_scxctl_version`); `None` if it wasn't detected. Only logged so
+    /// far: every invocation uses the same syntax whatever the version.
+    pub version: Option<ScxctlVersion>,
+}
+
+/// Executes scxctl for `ScxSettings`. Dropping the returned future must
+/// stop the command; that's how `timeout` is enforced.
+pub trait ScxRunner: std::fmt::Debug + Send + Sync {
+    /// `env` is the mode's `Mode::env` for start/switch, empty otherwise.
+    fn run<'a>(
+        &'a self,
+        bin: &'a Path,
+        args: &'a [OsString],
+        env: &'a [(String, Option<String>)],
+    ) -> BoxFuture<'a, io::Result<Output>>;
+
+    /// `run`, cut off after `limit`. The default drops `run`'s future, which
+    /// the `blocking` build can't do; runners that can stop the command
+    /// themselves override this.
+    fn run_limited<'a>(
+        &'a self,
+        bin: &'a Path,
+        args: &'a [OsString],
+        env: &'a [(String, Option<String>)],
+        limit: Duration,
+    ) -> BoxFuture<'a, io::Result<Option<Output>>> {
+        Box::pin(async move {
+            match rt::timeout(limit, self.run(bin, args, env)).await {
+                Ok(out) => out.map(Some),
+                Err(rt::Elapsed) => Ok(None),
+            }
+        })
+    }
+}
+
+/// Spawns `bin` as a child process with piped stdout/stderr.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessRunner;
+
+impl ProcessRunner {
+    fn command(
+        bin: &Path,
+        args: &[OsString],
+        env: &[(String, Option<String>)],
+    ) -> std::process::Command {
+        let mut cmd = std::process::Command::new(bin);
+        cmd.args(args).stdin(Stdio::null());
+        set_env(&mut cmd, env);
+        cmd
+    }
+}
+
+impl ScxRunner for ProcessRunner {
+    fn run<'a>(
+        &'a self,
+        bin: &'a Path,
+        args: &'a [OsString],
+        env: &'a [(String, Option<String>)],
+    ) -> BoxFuture<'a, io::Result<Output>> {
+        Box::pin(rt::output(Self::command(bin, args, env)))
+    }
+
+    fn run_limited<'a>(
+        &'a self,
+        bin: &'a Path,
+        args: &'a [OsString],
+        env: &'a [(String, Option<String>)],
+        limit: Duration,
+    ) -> BoxFuture<'a, io::Result<Option<Output>>> {
+        Box::pin(async move {
+            match rt::output_within(Self::command(bin, args, env), limit).await {
+                Ok(out) => out.map(Some),
+                Err(rt::Elapsed) => Ok(None),
+            }
+        })
+    }
+}
+
+/// Runtime switches for `apply_mode` that come from the command line rather
+/// than the config file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ApplyOptions {
+    /// Log the scxctl start/switch instead of running it.
+    pub dry_run: bool,
+    /// Apply even when scxctl already reports the same scheduler and args.
+    pub force: bool,
+}
+
+/// `bin` is either a bare name looked up in PATH or an explicit path.
+pub fn ensure_bin(bin: &Path) -> Result<(), ApplyError> {
+    let in_path = bin.components().count() <= 1;
+    let found = if in_path {
+        which::which(bin).is_ok()
+    } else {
+        bin.is_file()
+    };
+    if !found {
+        return Err(ApplyError::BinaryNotFound {
+            bin: bin.to_owned(),
+            in_path,
+        });
+    }
+    Ok(())
+}
+
+/// Add `env` to the environment `cmd` inherits; `None` removes a variable.
+fn set_env(cmd: &mut std::process::Command, env: &[(String, Option<String>)]) {
+    for (name, value) in env {
+        match value {
+            Some(value) => cmd.env(name, value),
+            None => cmd.env_remove(name),
+        };
+    }
+}
+
+/// Run scxctl with `scx.global_args` ahead of `args`, killing it if it
+/// hasn't exited within `scx.timeout`.
+async fn scxctl<I, S>(scx: &ScxSettings, args: I) -> Result<Output, ApplyError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    scxctl_with_env(scx, args, &[]).await
+}
+
+/// `scxctl` with `env` added to its environment.
+async fn scxctl_with_env<I, S>(
+    scx: &ScxSettings,
+    args: I,
+    env: &[(String, Option<String>)],
+) -> Result<Output, ApplyError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut argv: Vec<OsString> = scx.global_args.iter().map(OsString::from).collect();
+    let subcmd_at = argv.len();
+    argv.extend(args.into_iter().map(|a| a.as_ref().to_owned()));
+    match scx
+        .runner
+        .run_limited(&scx.bin, &argv, env, scx.timeout)
+        .await
+    {
+        Ok(Some(out)) => Ok(out),
+        Ok(None) => Err(ApplyError::Timeout {
+            subcmd: argv
+                .get(subcmd_at)
+                .map_or(String::new(), |a| a.to_string_lossy().into_owned()),
+            after: scx.timeout,
+        }),
+        Err(source) => Err(ApplyError::Exec {
+            bin: scx.bin.clone(),
+            source,
+        }),
+    }
+}
+
+/// The error for an `scxctl subcmd` that exited non-zero.
+fn scxctl_failed(subcmd: &str, out: &Output) -> ApplyError {
+    ApplyError::Scxctl {
+        subcmd: subcmd.to_owned(),
+        code: out.status.code().unwrap_or(-1),
+        stderr: String::from_utf8_lossy(&out.stderr).trim().to_owned(),
+    }
+}
+
+/// Scheduler names reported by `scxctl list`.
+pub async fn scx_schedulers(scx: &ScxSettings) -> Result<Vec<String>, ApplyError> {
+    let out = scxctl(scx, ["list"]).await?;
+    if !out.status.success() {
+        return Err(scxctl_failed("list", &out));
+    }
+    Ok(parse_scheduler_list(&String::from_utf8_lossy(&out.stdout)))
+}
+
+/// Accepts both `supported schedulers: ["a", "b"]` and one-name-per-line
+/// output.
+fn parse_scheduler_list(stdout: &str) -> Vec<String> {
+    let body = stdout.rsplit_once(':').map_or(stdout, |(_, rest)| rest);
+    body.split(|c: char| c.is_whitespace() || matches!(c, ',' | '[' | ']' | '"' | '\''))
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// An scxctl release, as `scxctl --version` reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ScxctlVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl ScxctlVersion {
+    /// The first `X.Y` or `X.Y.Z` word in `stdout`, e.g. `scxctl 1.0.14`; a
+    /// leading `v` and a `-rc1`/`+git` style suffix are allowed.
+    pub fn parse(stdout: &str) -> Option<Self> {
+        stdout.split_whitespace().find_map(|word| {
+            let word = word.strip_prefix('v').unwrap_or(word);
+            let core = word.split(['-', '+']).next()?;
+            let mut parts = core.split('.').map(|n| n.parse::<u32>().ok());
+            let major = parts.next()??;
+            let minor = parts.next()??;
+            let patch = parts.next().unwrap_or(Some(0))?;
+            parts.next().is_none().then_some(ScxctlVersion {
+                major,
+                minor,
+                patch,
+            })
+        })
+    }
+}
+
+impl fmt::Display for ScxctlVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Run `scxctl --version` and log what it reports. `None`, with a warning,
+/// when it fails or prints something without a version in it; that is not
+/// an error, as nothing depends on the version yet.
+pub async fn detect_scxctl_version(scx: &ScxSettings) -> Option<ScxctlVersion> {
+    let out = match scxctl(scx, ["--version"]).await {
+        Ok(out) if out.status.success() => out,
+        Ok(out) => {
+            warn!(
+                "[scxctl] version unknown: {}",
+                chain(&scxctl_failed("--version", &out))
+            );
+            return None;
+        }
+        Err(e) => {
+            warn!("[scxctl] version unknown: {}", chain(&e));
+            return None;
+        }
+    };
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    match ScxctlVersion::parse(&stdout) {
+        Some(version) => {
+            info!(%version, "[scxctl] detected version");
+            Some(version)
+        }
+        None => {
+            warn!("[scxctl] can't parse a version from {:?}", stdout.trim());
+            None
+        }
+    }
+}
+
+/// `scx_lavd` and `lavd` name the same scheduler.
+fn normalize_sched(name: &str) -> &str {
+    name.strip_prefix("scx_").unwrap_or(name)
+}
+
+/// Warn (or fail, when `strict`) about configured schedulers that scxctl
+/// doesn't know. If scxctl itself can't be queried we only warn.
+pub async fn check_schedulers(config: &Config, strict: bool) -> Result<(), ConfigError> {
+    let available = match scx_schedulers(&config.scx).await {
+        Ok(list) if !list.is_empty() => list,
+        Ok(_) => {
+            warn!("scxctl list returned no schedulers; skipping scheduler validation");
+            return Ok(());
+        }
+        Err(e) => {
+            warn!("skipping scheduler validation: {}", chain(&e));
+            return Ok(());
+        }
+    };
+
+    let mut unknown = Vec::new();
+    for (label, set) in config.labeled_mode_sets() {
+        for mode in set
+            .modes()
+            .into_iter()
+            .filter(|mode| mode.action == ModeAction::Run)
+        {
+            let sched = normalize_sched(&mode.sched);
+            if !available.iter().any(|a| normalize_sched(a) == sched) {
+                warn!(
+                    "mode '{label}' uses scheduler '{}' which scxctl does not list (available: {})",
+                    mode.sched,
+                    available.join(", ")
+                );
+                unknown.push(mode.sched.clone());
+            }
+        }
+    }
+
+    if strict && !unknown.is_empty() {
+        return Err(ConfigError::UnknownSchedulers(unknown));
+    }
+    Ok(())
+}
+
+/// Long options of a few common schedulers, for `check_sched_args`. Not
+/// exhaustive across versions; a flag missing here only costs a warning.
+const KNOWN_SCHED_FLAGS: [(&str, &[&str]); 3] = [
+    (
+        "rusty",
+        &[
+            "slice-us-underutil",
+            "slice-us-overutil",
+            "interval",
+            "tune-interval",
+            "load-half-life",
+            "cache-level",
+            "cpumasks",
+            "greedy-threshold",
+            "greedy-threshold-x-numa",
+            "no-load-balance",
+            "kthreads-local",
+            "balanced-kworkers",
+            "fifo-sched",
+            "direct-greedy-under",
+            "kick-greedy-under",
+            "direct-greedy-numa",
+            "partial",
+            "mempolicy-affinity",
+            "stats",
+            "monitor",
+            "exit-dump-len",
+            "verbose",
+            "version",
+            "help-stats",
+        ],
+    ),
+    (
+        "lavd",
+        &[
+            "autopilot",
+            "autopower",
+            "performance",
+            "powersave",
+            "balanced",
+            "slice-max-us",
+            "slice-min-us",
+            "preempt-shift",
+            "cpu-pref-order",
+            "no-use-em",
+            "no-futex-boost",
+            "no-preemption",
+            "no-wake-sync",
+            "no-core-compaction",
+            "no-freq-scaling",
+            "per-cpu-dsq",
+            "stats",
+            "monitor",
+            "monitor-sched-samples",
+            "log-level",
+            "exit-dump-len",
+            "verbose",
+            "version",
+            "help-stats",
+        ],
+    ),
+    (
+        "bpfland",
+        &[
+            "slice-us",
+            "slice-us-min",
+            "slice-us-lag",
+            "throttle-us",
+            "idle-resume-us",
+            "no-preempt",
+            "local-pcpu",
+            "local-kthreads",
+            "no-wake-sync",
+            "sticky-tasks",
+            "primary-domain",
+            "disable-l2",
+            "disable-l3",
+            "disable-smt",
+            "disable-numa",
+            "cpufreq",
+            "stats",
+            "monitor",
+            "exit-dump-len",
+            "verbose",
+            "version",
+            "help-stats",
+        ],
+    ),
+];
+
+/// Long options in modes' `args` that their scheduler doesn't take, as
+/// warnings for `--verify-config`, naming the schedulers that do take them.
+/// Only schedulers in `KNOWN_SCHED_FLAGS` are checked, and short options
+/// not at all.
+pub fn check_sched_args(config: &Config) -> Vec<String> {
+    let flags_of = |sched: &str| {
+        KNOWN_SCHED_FLAGS
+            .iter()
+            .find(|(name, _)| *name == normalize_sched(sched))
+            .map(|(_, flags)| *flags)
+    };
+    let mut found = Vec::new();
+    for (label, set) in config.labeled_mode_sets() {
+        for mode in set.modes() {
+            let Some(accepted) = flags_of(&mode.sched) else {
+                continue;
+            };
+            let flags = mode
+                .args
+                .iter()
+                .take_while(|arg| *arg != "--")
+                .filter_map(|arg| arg.strip_prefix("--"));
+            for flag in flags {
+                let flag = flag.split_once('=').map_or(flag, |(name, _)| name);
+                if accepted.contains(&flag) {
+                    continue;
+                }
+                let owners: Vec<&str> = KNOWN_SCHED_FLAGS
+                    .iter()
+                    .filter(|(_, flags)| flags.contains(&flag))
+                    .map(|(name, _)| *name)
+                    .collect();
+                let whose = if owners.is_empty() {
+                    String::new()
+                } else {
+                    format!(" (it is a flag of {})", owners.join(", "))
+                };
+                found.push(format!(
+                    "mode '{label}': {} does not take --{flag}{whose}",
+                    mode.sched
+                ));
+            }
+        }
+    }
+    found
+}
+
+/// What `scxctl get` says is loaded right now.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScxStatus {
+    Stopped,
+    Running {
+        /// `None` when the output named no scheduler we could pick out.
+        sched: Option<String>,
+        /// `None` when the output didn't report arguments at all (as opposed
+        /// to reporting an empty list).
+        args: Option<Vec<String>>,
+    },
+}
+
+impl ScxStatus {
+    /// Whether any scheduler is loaded.
+    pub fn is_running(&self) -> bool {
+        matches!(self, ScxStatus::Running { .. })
+    }
+
+    /// True only when both scheduler and args are known and equal to `mode`;
+    /// for an `action: stop` mode, when nothing is running.
+    pub fn matches(&self, mode: &Mode) -> bool {
+        if mode.action == ModeAction::Stop {
+            return matches!(self, ScxStatus::Stopped);
+        }
+        match self {
+            ScxStatus::Running {
+                sched: Some(sched),
+                args: Some(args),
+            } => {
+                // Compare what scxctl actually receives: the whitespace-joined payload.
+                let joined = mode.args.join(" ");
+                normalize_sched(sched) == normalize_sched(&mode.sched)
+                    && args
+                        .iter()
+                        .map(String::as_str)
+                        .eq(joined.split_whitespace())
+            }
+            _ => false,
+        }
+    }
+
+    /// Like `matches`, but a status without args (`running lavd in Auto
+    /// mode`) only needs the scheduler to agree: for checking that a mode
+    /// came up, not for skipping an apply.
+    pub fn agrees_with(&self, mode: &Mode) -> bool {
+        match self {
+            ScxStatus::Running {
+                sched: Some(sched),
+                args: None,
+            } if mode.action == ModeAction::Run => {
+                normalize_sched(sched) == normalize_sched(&mode.sched)
+            }
+            _ => self.matches(mode),
+        }
+    }
+
+    /// One-line rendering in the same shape as `Mode::describe`.
+    pub fn describe(&self) -> String {
+        match self {
+            ScxStatus::Stopped => "stopped".to_owned(),
+            ScxStatus::Running { sched, args } => {
+                let sched = sched.as_deref().unwrap_or("<unknown scheduler>");
+                match args {
+                    Some(args) if !args.is_empty() => format!("{sched} --args={}", args.join(" ")),
+                    Some(_) => sched.to_owned(),
+                    None => format!("{sched} (args not reported)"),
+                }
+            }
+        }
+    }
+
+    /// Parse `scxctl get` stdout. Known shapes:
+    ///
+    /// - `no scx scheduler running` (or another of `NOT_RUNNING`), or nothing
+    /// - `running lavd in Auto mode`
+    /// - `running flash with args: -m all`
+    /// - `running scx_rusty with arguments "-s 20000"`
+    /// - `running bpfland with args ["-m", "all"]`
+    ///
+    /// Anything else counts as running an unknown scheduler, so an apply
+    /// uses `switch`, which scxctl at worst refuses, rather than a `start`
+    /// that would fail against a loaded scheduler.
+    pub fn parse(stdout: &str) -> Self {
+        let text = stdout.trim();
+        let lower = text.to_lowercase();
+        if text.is_empty() || says_stopped(&lower) || matches!(lower.as_str(), "stopped" | "none") {
+            return ScxStatus::Stopped;
+        }
+
+        let Some(after) = find_word(text, "running") else {
+            return ScxStatus::Running {
+                sched: None,
+                args: None,
+            };
+        };
+        let mut words = after.splitn(2, char::is_whitespace);
+        let sched = words
+            .next()
+            .map(|w| w.trim_matches(|c: char| matches!(c, '"' | '\'' | ',' | ':')))
+            .filter(|w| !w.is_empty())
+            .map(str::to_owned);
+        let rest = words.next().unwrap_or("").trim();
+
+        let args = ["with arguments", "with args"]
+            .iter()
+            .find_map(|marker| find_word(rest, marker))
+            .map(|raw| {
+                let raw = raw.trim_start_matches(':').trim();
+                let raw = raw
+                    .strip_prefix('[')
+                    .and_then(|r| r.strip_suffix(']'))
+                    .unwrap_or(raw);
+                let raw = raw.trim_matches(|c: char| matches!(c, '"' | '\''));
+                raw.split(|c: char| c.is_whitespace() || c == ',')
+                    .map(|t| t.trim_matches(|c: char| matches!(c, '"' | '\'')))
+                    .filter(|t| !t.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            });
+
+        ScxStatus::Running { sched, args }
+    }
+
+    /// `parse`, taking the exit status of `scxctl get` into account: when
+    /// it failed, output naming no scheduler is an error message rather
+    /// than a status, and scx_loader not answering means nothing is loaded.
+    pub fn from_get(stdout: &str, success: bool) -> Self {
+        match ScxStatus::parse(stdout) {
+            ScxStatus::Running { sched: None, .. } if !success => ScxStatus::Stopped,
+            status => status,
+        }
+    }
+}
+
+/// How different scxctl releases start saying that no scheduler is loaded,
+/// in lowercase.
+const NOT_RUNNING: &[&str] = &[
+    "no scx scheduler running",
+    "no scheduler running",
+    "no scheduler is running",
+    "no scheduler loaded",
+];
+
+/// Endings that only mean "stopped" when the scheduler or scx itself is
+/// the whole subject (`scx_loader: not running`); elsewhere in the output
+/// they may be about something else, e.g. `running rusty (previous
+/// instance is stopped)`.
+const STOPPED_ENDINGS: &[&str] = &["is not running", "not running", "is stopped"];
+
+/// Whether the trimmed, lowercased `get` output says nothing is loaded.
+fn says_stopped(lower: &str) -> bool {
+    if NOT_RUNNING.iter().any(|phrase| lower.starts_with(phrase)) {
+        return true;
+    }
+    let lower = lower.trim_end_matches('.');
+    STOPPED_ENDINGS.iter().any(|ending| {
+        lower
+            .strip_suffix(ending)
+            .map(|subject| subject.trim_end().trim_end_matches(':'))
+            .is_some_and(|subject| {
+                matches!(subject, "scheduler" | "scx scheduler")
+                    || (subject.starts_with("scx") && !subject.contains(char::is_whitespace))
+            })
+    })
+}
+
+/// Text following the first case-insensitive occurrence of the ASCII
+/// `word`. ASCII lowercasing keeps byte offsets, so the index is valid in
+/// `text` whatever else it contains.
+fn find_word<'a>(text: &'a str, word: &str) -> Option<&'a str> {
+    let idx = text.to_ascii_lowercase().find(&word.to_ascii_lowercase())?;
+    Some(text[idx + word.len()..].trim_start())
+}
+
+/// Query `scxctl get`. A non-zero exit is logged and the output parsed anyway.
+pub async fn scx_status(scx: &ScxSettings) -> Result<ScxStatus, ApplyError> {
+    let out = scxctl(scx, ["get"]).await?;
+    // If scxctl itself errors, treat as not running (unless stdout still
+    // names a scheduler) but log.
+    if !out.status.success() {
+        warn!(
+            "scxctl get exit={} stderr={}",
+            out.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    let status = ScxStatus::from_get(&String::from_utf8_lossy(&out.stdout), out.status.success());
+    debug!(?status, "[scxctl] get");
+    Ok(status)
+}
+
+/// Whether a sched_ext scheduler is loaded.
+pub async fn scx_running(scx: &ScxSettings) -> Result<bool, ApplyError> {
+    Ok(scx_status(scx).await?.is_running())
+}
+
+/// `scxctl stop`: hand scheduling back to the kernel's default scheduler.
+pub async fn stop_scheduler(scx: &ScxSettings, opts: &ApplyOptions) -> Result<(), ApplyError> {
+    if opts.dry_run {
+        info!("[dry-run] scxctl stop");
+        return Ok(());
+    }
+    info!("[stop] scxctl stop");
+    let out = scxctl(scx, ["stop"]).await?;
+    if !out.status.success() {
+        return Err(scxctl_failed("stop", &out));
+    }
+    Ok(())
+}
+
+/// Make `mode` the running scheduler: pre hooks, `scxctl start` or `switch`
+/// (skipped when it is already running with the same args), post hooks.
+///
+/// Logs `[apply] finished` with the time taken, for per-switch latency. A
+/// caller's span with a `subcmd` field gets the subcommand recorded in it.
+pub async fn apply_mode(
+    mode: &Mode,
+    scx: &ScxSettings,
+    opts: &ApplyOptions,
+) -> Result<(), ApplyError> {
+    let started = std::time::Instant::now();
+    let result = match probe_for_apply(scx, opts).await {
+        Ok(status) => apply_mode_with_status(mode, &status, scx, opts).await,
+        Err(e) => Err(e),
+    };
+    let result = result.map_err(|e| explain_missing_bin(e, &scx.bin));
+    info!(
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        ok = result.is_ok(),
+        "[apply] finished"
+    );
+    result
+}
+
+/// `ensure_bin` only runs at startup, so a package upgrade that removes or
+/// replaces scxctl later shows up as a bare "No such file or directory" from
+/// exec. Say so, and check again whether it's there now.
+fn explain_missing_bin(e: ApplyError, bin: &Path) -> ApplyError {
+    if !e.is_exec_not_found() {
+        return e;
+    }
+    ApplyError::BinaryGone {
+        bin: bin.to_owned(),
+        back: ensure_bin(bin).is_ok(),
+        source: Box::new(e),
+    }
+}
+
+/// `scx_status` as `apply_mode` sees it: in a dry run a failed probe counts
+/// as no scheduler running.
+pub async fn probe_for_apply(
+    scx: &ScxSettings,
+    opts: &ApplyOptions,
+) -> Result<ScxStatus, ApplyError> {
+    if opts.dry_run {
+        // scxctl may not even be installed on the machine doing the dry run.
+        Ok(scx_status(scx).await.unwrap_or_else(|e| {
+            warn!(
+                "[dry-run] probe failed, assuming no scheduler running: {}",
+                chain(&e)
+            );
+            ScxStatus::Stopped
+        }))
+    } else {
+        scx_status(scx)
+            .await
+            .map_err(|e| ApplyError::Probe(Box::new(e)))
+    }
+}
+
+/// `apply_mode` against an already probed `status`, so callers that need
+/// the scheduler state for their own decisions don't run `scxctl get` twice.
+/// Both the skip check and the choice between `start` and `switch` use it.
+pub async fn apply_mode_with_status(
+    mode: &Mode,
+    status: &ScxStatus,
+    scx: &ScxSettings,
+    opts: &ApplyOptions,
+) -> Result<(), ApplyError> {
+    // Written even when the scheduler is left alone: EPP can drift on its own
+    // (other tools, firmware), and it's never worth failing the apply over.
+    if let Some(epp) = &mode.epp {
+        if opts.dry_run {
+            info!("[dry-run] epp {epp}");
+        } else {
+            write_epp(Path::new(CPU_SYSFS), epp);
+        }
+    }
+    if mode.action == ModeAction::Stop {
+        return stop_for_mode(mode, status, scx, opts).await;
+    }
+    let description = mode.description.as_deref();
+    if status.matches(mode) {
+        if !opts.force {
+            info!(sched = %mode.sched, description, "[apply] already running with the same args; skipping");
+            return Ok(());
+        }
+        info!(sched = %mode.sched, description, "[apply] already running; re-applying (--force)");
+    }
+    let running = status.is_running();
+    let subcmd = match mode.force_subcmd {
+        Some(subcmd) => subcmd,
+        None if running => ScxSubcommand::Switch,
+        None => ScxSubcommand::Start,
+    };
+    // `start` refuses while another scheduler is loaded.
+    let stop_first = running && subcmd == ScxSubcommand::Start;
+    let subcmd = subcmd.as_str();
+    tracing::Span::current().record("subcmd", subcmd);
+    let joined = mode.args.join(" ");
+
+    let mut argv = vec![subcmd.to_owned(), "--sched".to_owned(), mode.sched.clone()];
+    match mode.args_style {
+        // Keep the entire args payload as *one* argument: --args=".."
+        ArgsStyle::Joined => argv.push(format!("--args={joined}")),
+        ArgsStyle::List if mode.args.is_empty() => {}
+        ArgsStyle::List => {
+            argv.push("--args".to_owned());
+            argv.extend(mode.args.iter().cloned());
+        }
+    }
+
+    if opts.dry_run {
+        for cmd in &mode.pre {
+            info!("[dry-run] pre hook: {cmd}");
+        }
+        if stop_first {
+            info!("[dry-run] scxctl stop");
+        }
+        let line: Vec<String> = scx
+            .global_args
+            .iter()
+            .chain(&argv)
+            .map(|a| shell_quote(a))
+            .collect();
+        info!("[dry-run] scxctl {}", line.join(" "));
+        for cmd in &mode.post {
+            info!("[dry-run] post hook: {cmd}");
+        }
+        return Ok(());
+    }
+
+    for cmd in &mode.pre {
+        run_hook("pre", cmd, &mode.env)
+            .await
+            .map_err(|e| ApplyError::PreHook(Box::new(e)))?;
+    }
+
+    if stop_first {
+        stop_scheduler(scx, opts)
+            .await
+            .map_err(|e| ApplyError::StopFirst(Box::new(e)))?;
+    }
+
+    info!(
+        subcmd,
+        sched = %mode.sched,
+        args = %joined,
+        description,
+        "[apply]"
+    );
+
+    // Non-zero exits are retried (scx_loader may still be coming up at boot);
+    // failing to exec at all is not.
+    let mut delay = scx.retry_delay;
+    let mut attempt = 0;
+    let out = loop {
+        let out = scxctl_with_env(scx, &argv, &mode.env).await?;
+        if out.status.success() || attempt >= scx.retries {
+            break out;
+        }
+        attempt += 1;
+        warn!(
+            attempt,
+            retries = scx.retries,
+            "scxctl {subcmd} failed (exit={}): {}; retrying in {delay:?}",
+            out.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+        rt::sleep(delay).await;
+        delay *= 2;
+    };
+
+    let stdout = String::from_utf8_lossy(&out.stdout).trim().to_owned();
+    let stderr = String::from_utf8_lossy(&out.stderr).trim().to_owned();
+
+    if !out.status.success() {
+        Err(scxctl_failed(subcmd, &out))
+    } else {
+        if !stdout.is_empty() {
+            info!("[scxctl] {stdout}");
+        }
+        // Failures carry stderr in the error; on success it's the only place
+        // a warning such as an ignored flag would show up.
+        if !stderr.is_empty() {
+            warn!("[scxctl] {subcmd} succeeded with stderr output: {stderr}");
+        }
+        if let Some(limit) = scx.verify {
+            verify_running(mode, scx, limit).await;
+        }
+        for cmd in &mode.post {
+            if let Err(e) = run_hook("post", cmd, &mode.env).await {
+                warn!("{}", chain(&e));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `action: stop` side of `apply_mode_with_status`: pre hooks, `scxctl
+/// stop`, post hooks, or nothing at all when no scheduler is loaded.
+async fn stop_for_mode(
+    mode: &Mode,
+    status: &ScxStatus,
+    scx: &ScxSettings,
+    opts: &ApplyOptions,
+) -> Result<(), ApplyError> {
+    let description = mode.description.as_deref();
+    if !status.is_running() {
+        info!(description, "[apply] no scheduler running; nothing to stop");
+        return Ok(());
+    }
+    tracing::Span::current().record("subcmd", "stop");
+    if opts.dry_run {
+        for cmd in &mode.pre {
+            info!("[dry-run] pre hook: {cmd}");
+        }
+        stop_scheduler(scx, opts).await?;
+        for cmd in &mode.post {
+            info!("[dry-run] post hook: {cmd}");
+        }
+        return Ok(());
+    }
+
+    for cmd in &mode.pre {
+        run_hook("pre", cmd, &mode.env)
+            .await
+            .map_err(|e| ApplyError::PreHook(Box::new(e)))?;
+    }
+    info!(description, "[apply] action: stop");
+    stop_scheduler(scx, opts).await?;
+    for cmd in &mode.post {
+        if let Err(e) = run_hook("post", cmd, &mode.env).await {
+            warn!("{}", chain(&e));
+        }
+    }
+    Ok(())
+}
+
+/// Poll `scxctl get` until it reports `mode.sched` running, for up to
+/// `limit`. A scheduler that scxctl accepted but that failed to attach only
+/// shows up this way; it is logged, not treated as a failed apply.
+async fn verify_running(mode: &Mode, scx: &ScxSettings, limit: Duration) {
+    let deadline = Instant::now() + limit;
+    loop {
+        let seen = match scx_status(scx).await {
+            Ok(ScxStatus::Running {
+                sched: Some(sched), ..
+            }) if normalize_sched(&sched) == normalize_sched(&mode.sched) => {
+                debug!(sched = %mode.sched, "[verify] scheduler is running");
+                return;
+            }
+            Ok(status) => status.describe(),
+            Err(e) => chain(&e).to_string(),
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            warn!(
+                "[verify] asked for {} but scxctl still reports {seen} after {limit:?}",
+                mode.sched
+            );
+            return;
+        }
+        rt::sleep(VERIFY_POLL_INTERVAL.min(deadline - now)).await;
+    }
+}
+
+/// Run one hook through `/bin/sh -c`, inheriting our environment plus `env`,
+/// and log its output. Non-zero exit is an error.
+async fn run_hook(
+    phase: &'static str,
+    cmd: &str,
+    env: &[(String, Option<String>)],
+) -> Result<(), ApplyError> {
+    info!(phase, cmd, "[hook]");
+    let mut sh = std::process::Command::new("/bin/sh");
+    sh.arg("-c").arg(cmd);
+    set_env(&mut sh, env);
+    let out = rt::output(sh)
+        .await
+        .map_err(|source| ApplyError::HookExec {
+            phase,
+            cmd: cmd.to_owned(),
+            source,
+        })?;
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    if !stdout.trim().is_empty() {
+        info!("[hook:{phase}] {}", stdout.trim());
+    }
+    if !stderr.trim().is_empty() {
+        warn!("[hook:{phase}] {}", stderr.trim());
+    }
+
+    if !out.status.success() {
+        return Err(ApplyError::HookFailed {
+            phase,
+            cmd: cmd.to_owned(),
+            code: out.status.code().unwrap_or(-1),
+        });
+    }
+    Ok(())
+}
+
+/// Write `value` to `cpu*/cpufreq/energy_performance_preference` under
+/// `cpu_root`. CPUs without the attribute are skipped; failures are logged.
+fn write_epp(cpu_root: &Path, value: &str) {
+    let entries = match fs::read_dir(cpu_root) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("[epp] cannot list {}: {e}", cpu_root.display());
+            return;
+        }
+    };
+    let mut written = 0usize;
+    let mut failed = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let is_cpu = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("cpu"))
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+        if !is_cpu {
+            continue;
+        }
+        let attr = entry.path().join("cpufreq/energy_performance_preference");
+        if !attr.exists() {
+            continue;
+        }
+        match fs::write(&attr, value) {
+            Ok(()) => written += 1,
+            Err(e) => failed.push(format!("{}: {e}", name.to_string_lossy())),
+        }
+    }
+    match (written, failed.is_empty()) {
+        (0, true) => warn!("[epp] no CPU exposes energy_performance_preference; '{value}' not set"),
+        (_, true) => info!(epp = value, cpus = written, "[epp]"),
+        // Usually the same error on every CPU (e.g. permissions); show one.
+        (_, false) => warn!(
+            "[epp] wrote '{value}' to {written} CPUs, failed on {} (first: {})",
+            failed.len(),
+            failed[0]
+        ),
+    }
+}
+
+/// Quote `arg` for display so a logged command line can be pasted into a shell.
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_=./:,+@%".contains(c))
+    {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
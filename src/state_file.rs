@@ -0,0 +1,68 @@
+//! The last applied mode as a small JSON file, for status bars and scripts
+//! that don't speak D-Bus. Enabled by `state_file` in the config.
+
+use anyhow::{Context, Result};
+use scx_power_sync_dbus::{Mode, Profile};
+use serde::Serialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+#[derive(Serialize)]
+struct State<'a> {
+    profile: &'a str,
+    sched: &'a str,
+    args: &'a [String],
+    description: Option<&'a str>,
+    preset: Option<&'a str>,
+    /// Seconds since the Unix epoch at which the mode was applied.
+    timestamp: u64,
+}
+
+/// Replace `path` with `mode`, just applied for `profile`. The file is
+/// written next to it and renamed over it, so readers never see half of it.
+/// Failures are only logged.
+pub fn write(path: &Path, profile: Profile, mode: &Mode, preset: Option<&str>) {
+    let state = State {
+        profile: profile.as_config_key(),
+        sched: &mode.sched,
+        args: &mode.args,
+        description: mode.description.as_deref(),
+        preset,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    };
+    if let Err(e) = replace(path, &state) {
+        warn!("[state-file] {e:#}");
+    }
+}
+
+fn replace(path: &Path, state: &State) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    }
+    let mut json = serde_json::to_vec_pretty(state).context("serialize state")?;
+    json.push(b'\n');
+    let tmp = temp_path(path);
+    fs::write(&tmp, json).with_context(|| format!("write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("rename over {}", path.display()))
+}
+
+/// `dir/.name.tmp`: same directory, so the rename stays on one filesystem.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.tmp"))
+}
+
+/// Delete the file on a clean exit (or when the config points elsewhere), so
+/// a stale one doesn't outlive the daemon.
+pub fn remove(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => warn!("[state-file] remove {}: {e}", path.display()),
+    }
+}
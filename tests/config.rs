@@ -0,0 +1,474 @@
+//! `load_config` against config files written to a scratch directory.
+
+use scx_power_sync_dbus::{
+    Config, DecodeError, ModeAction, PowerSource, Profile, check_sched_args, decode_active_profile,
+    load_config, migrate_config,
+};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use zbus::zvariant::Value;
+
+/// A config file in its own directory, removed again on drop.
+struct ConfigFile {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl ConfigFile {
+    fn new(contents: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "scx-power-sync-config-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.yaml");
+        fs::write(&path, contents).unwrap();
+        ConfigFile { dir, path }
+    }
+
+    fn load(&self) -> anyhow::Result<Config> {
+        Ok(load_config(Some(&self.path))?)
+    }
+
+    /// The full error chain from loading, which must fail.
+    fn load_err(&self) -> String {
+        match self.load() {
+            Ok(_) => panic!("{} loaded, expected an error", self.path.display()),
+            Err(e) => format!("{e:#}"),
+        }
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+const VALID: &str = "\
+version: 1
+debounce_ms: 50
+modes:
+  performance: { sched: flash, args: ['-m', all] }
+  balanced: { sched: lavd, args: ['--autopilot'] }
+  power-saver: { sched: flash, args: [] }
+";
+
+#[test]
+fn valid_config_loads() {
+    let file = ConfigFile::new(VALID);
+    let config = file.load().unwrap();
+    assert_eq!(config.path, file.path);
+    assert_eq!(config.debounce.as_millis(), 50);
+
+    let balanced = config.mode_for(Profile::Balanced, PowerSource::Ac).unwrap();
+    assert_eq!(balanced.sched, "lavd");
+    assert_eq!(balanced.args, ["--autopilot"]);
+    let performance = config
+        .mode_for(Profile::Performance, PowerSource::Battery)
+        .unwrap();
+    assert_eq!(performance.args, ["-m", "all"]);
+    assert!(
+        config
+            .mode_for(Profile::PowerSaver, PowerSource::Ac)
+            .unwrap()
+            .args
+            .is_empty()
+    );
+}
+
+#[test]
+fn unknown_profile_key_is_rejected() {
+    let file = ConfigFile::new(&format!("{VALID}  turbo: {{ sched: rusty, args: [] }}\n"));
+    let err = file.load_err();
+    assert!(err.contains("unknown profile 'turbo'"), "{err}");
+}
+
+#[test]
+fn unknown_profile_key_in_preset_names_the_preset() {
+    let file = ConfigFile::new(&format!(
+        "{VALID}presets:\n  quiet:\n    quiet: {{ sched: rusty, args: [] }}\n"
+    ));
+    let err = file.load_err();
+    assert!(err.contains("unknown profile 'quiet.quiet'"), "{err}");
+}
+
+#[test]
+fn duplicate_profile_key_is_rejected() {
+    let file = ConfigFile::new(&format!(
+        "{VALID}  balanced: {{ sched: rusty, args: [] }}\n"
+    ));
+    let err = file.load_err();
+    assert!(
+        err.contains(&format!(
+            "duplicate configuration for profile 'balanced' in {}",
+            file.path.display()
+        )),
+        "{err}"
+    );
+}
+
+#[test]
+fn each_missing_profile_is_reported() {
+    for missing in Profile::all() {
+        let key = missing.as_config_key();
+        let contents: String = VALID
+            .lines()
+            .filter(|line| !line.trim_start().starts_with(&format!("{key}:")))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        let err = ConfigFile::new(&contents).load_err();
+        assert!(
+            err.contains(&format!("missing profile '{key}' (and no 'default' mode)")),
+            "{err}"
+        );
+    }
+}
+
+#[test]
+fn duplicate_key_in_preset_is_rejected() {
+    let file = ConfigFile::new(&format!(
+        "{VALID}presets:\n  quiet:\n    balanced: {{ sched: rusty, args: [] }}\n    balanced: {{ sched: lavd, args: [] }}\n"
+    ));
+    let err = file.load_err();
+    assert!(
+        err.contains("duplicate configuration for profile 'quiet.balanced'"),
+        "{err}"
+    );
+}
+
+#[test]
+fn duplicate_default_and_preset_names_are_rejected() {
+    let file = ConfigFile::new(
+        "modes:\n  default: { sched: lavd, args: [] }\n  default: { sched: rusty, args: [] }\n",
+    );
+    let err = file.load_err();
+    assert!(err.contains("duplicate 'default' mode"), "{err}");
+
+    let preset = "  quiet:\n    default: { sched: rusty, args: [] }\n";
+    let file = ConfigFile::new(&format!("{VALID}presets:\n{preset}{preset}"));
+    let err = file.load_err();
+    assert!(err.contains("duplicate preset 'quiet'"), "{err}");
+}
+
+#[test]
+fn default_mode_covers_missing_profiles() {
+    let file = ConfigFile::new(
+        "modes:\n  default: { sched: bpfland, args: [] }\n  balanced: { sched: lavd, args: [] }\n",
+    );
+    let config = file.load().unwrap();
+    assert_eq!(
+        config
+            .mode_for(Profile::PowerSaver, PowerSource::Ac)
+            .unwrap()
+            .sched,
+        "bpfland"
+    );
+    assert_eq!(
+        config
+            .mode_for(Profile::Balanced, PowerSource::Ac)
+            .unwrap()
+            .sched,
+        "lavd"
+    );
+}
+
+#[test]
+fn missing_profiles_allowed_when_not_required() {
+    let file = ConfigFile::new(
+        "require_all_profiles: false\nmodes:\n  balanced: { sched: lavd, args: [] }\n",
+    );
+    let config = file.load().unwrap();
+    assert!(
+        config
+            .mode_for(Profile::Performance, PowerSource::Ac)
+            .is_none()
+    );
+}
+
+#[test]
+fn missing_explicit_path_is_an_error() {
+    let file = ConfigFile::new(VALID);
+    let path = file.dir.join("absent.yaml");
+    let err = match load_config(Some(&path)) {
+        Ok(_) => panic!("loaded a file that doesn't exist"),
+        Err(e) => e.to_string(),
+    };
+    assert_eq!(
+        err,
+        format!("configuration file not found: {}", path.display())
+    );
+}
+
+#[test]
+fn active_profile_decodes_to_a_mapped_profile() {
+    let config = ConfigFile::new(&format!("aliases:\n  eco: power-saver\n{VALID}"))
+        .load()
+        .unwrap();
+    let decode = |value: Value| decode_active_profile(&value, &config);
+    let nested = Value::Value(Box::new(Value::Value(Box::new(Value::from(" balanced\n")))));
+    assert_eq!(decode(nested).unwrap(), Profile::Balanced);
+    assert_eq!(decode(Value::from("eco")).unwrap(), Profile::PowerSaver);
+    assert!(matches!(
+        decode(Value::from("")),
+        Err(DecodeError::NoActiveProfile)
+    ));
+    let err = decode(Value::from("turbo")).unwrap_err();
+    assert!(matches!(err, DecodeError::Unmapped(_)), "{err:?}");
+    assert!(
+        err.to_string().contains("unknown power profile: turbo"),
+        "{err}"
+    );
+    let err = decode(Value::from(3u32)).unwrap_err();
+    assert!(matches!(err, DecodeError::NotAString(_)), "{err:?}");
+}
+
+#[test]
+fn aliases_map_extra_profile_names() {
+    let file = ConfigFile::new(&format!("aliases:\n  eco: power-saver\n{VALID}"));
+    let config = file.load().unwrap();
+    assert_eq!(config.resolve_profile("eco").unwrap(), Profile::PowerSaver);
+    assert_eq!(
+        config.resolve_profile("balanced").unwrap(),
+        Profile::Balanced
+    );
+    let err = config.resolve_profile("turbo").unwrap_err().to_string();
+    assert!(err.contains("unknown power profile: turbo"), "{err}");
+
+    let err = ConfigFile::new(&format!("aliases:\n  eco: turbo\n{VALID}")).load_err();
+    assert!(err.contains("aliases entry 'eco'"), "{err}");
+    let err = ConfigFile::new(&format!("aliases:\n  balanced: power-saver\n{VALID}")).load_err();
+    assert!(err.contains("already a profile name"), "{err}");
+}
+
+#[test]
+fn top_level_sched_is_inherited() {
+    let file = ConfigFile::new(
+        "sched: rusty\n\
+         modes:\n  \
+           performance: { args: ['-m', all] }\n  \
+           balanced: { sched: lavd, args: [] }\n  \
+           power-saver:\n    \
+             ac: { args: [] }\n    \
+             battery: { args: ['--powersave'] }\n",
+    );
+    let config = file.load().unwrap();
+    let sched = |p, power| config.mode_for(p, power).unwrap().sched.clone();
+    assert_eq!(sched(Profile::Performance, PowerSource::Ac), "rusty");
+    assert_eq!(sched(Profile::Balanced, PowerSource::Ac), "lavd");
+    assert_eq!(sched(Profile::PowerSaver, PowerSource::Battery), "rusty");
+
+    let err = ConfigFile::new(&VALID.replace("sched: flash, ", "")).load_err();
+    assert!(
+        err.contains("missing field `sched` (and no top-level `sched`)"),
+        "{err}"
+    );
+}
+
+#[test]
+fn stop_action_needs_no_sched() {
+    let file = ConfigFile::new(
+        "sched: rusty\n\
+         modes:\n  \
+           performance: { args: [] }\n  \
+           balanced: { args: [] }\n  \
+           power-saver: { action: stop, post: ['true'] }\n",
+    );
+    let config = file.load().unwrap();
+    let mode = config
+        .mode_for(Profile::PowerSaver, PowerSource::Ac)
+        .unwrap();
+    assert_eq!(mode.action, ModeAction::Stop);
+    assert_eq!(mode.sched, "");
+    assert_eq!(mode.sched_label(), "stop");
+    assert_eq!(mode.post, ["true"]);
+
+    let err = ConfigFile::new(&VALID.replace("sched: flash, ", "action: stop, ")).load_err();
+    assert!(
+        err.contains("`sched`, `args` and `force_subcmd` don't apply to `action: stop`"),
+        "{err}"
+    );
+}
+
+#[test]
+fn empty_sched_is_rejected() {
+    for sched in ["''", "' '"] {
+        let contents = VALID.replace("sched: lavd", &format!("sched: {sched}"));
+        let err = ConfigFile::new(&contents).load_err();
+        assert!(err.contains("mode 'balanced'"), "{err}");
+        assert!(err.contains("`sched` is empty"), "{err}");
+    }
+}
+
+#[test]
+fn profile_placeholder_is_filled_in_per_profile() {
+    let file = ConfigFile::new(
+        "modes:\n  default: { sched: lavd, args: '--tag {profile} --cpus {0,1}' }\n",
+    );
+    let mode = file
+        .load()
+        .unwrap()
+        .mode_for(Profile::PowerSaver, PowerSource::Ac)
+        .unwrap()
+        .for_profile(Profile::PowerSaver);
+    assert_eq!(mode.args, ["--tag", "power-saver", "--cpus", "{0,1}"]);
+
+    let err = ConfigFile::new(&VALID.replace("'--autopilot'", "'{profle}'")).load_err();
+    assert!(err.contains("unknown placeholder '{profle}'"), "{err}");
+}
+
+#[test]
+fn mode_env_can_unset_variables() {
+    let file = ConfigFile::new(
+        "modes:\n  default:\n    sched: lavd\n    args: []\n    env: { RUST_LOG: debug, LC_ALL: ~, LANG: '' }\n",
+    );
+    let config = file.load().unwrap();
+    let mode = config.mode_for(Profile::Balanced, PowerSource::Ac).unwrap();
+    assert_eq!(
+        mode.env,
+        [
+            ("LANG".to_owned(), None),
+            ("LC_ALL".to_owned(), None),
+            ("RUST_LOG".to_owned(), Some("debug".to_owned())),
+        ]
+    );
+}
+
+#[test]
+fn flags_of_another_scheduler_are_flagged() {
+    let file = ConfigFile::new(
+        "require_all_profiles: false\n\
+         modes:\n  \
+           performance: { sched: scx_lavd, args: ['--performance', '--slice-us=5000'] }\n  \
+           balanced: { sched: bpfland, args: ['-s', '5000', '--no-preempt', '--', '--anything'] }\n  \
+           power-saver: { sched: flash, args: ['--made-up'] }\n",
+    );
+    let config = file.load().unwrap();
+    assert_eq!(
+        check_sched_args(&config),
+        ["mode 'performance': scx_lavd does not take --slice-us (it is a flag of bpfland)"]
+    );
+}
+
+#[test]
+fn migration_covers_every_mode_table() {
+    let legacy = "\
+modes:
+  performance: { sched: flash, args: '-m all' }
+  balanced: { sched: lavd, args: '--autopilot' }
+  power-saver: { sched: flash, args: '' }
+presets:
+  quiet:
+    performance: { sched: lavd, args: '--powersave --slice-max-us 5000' }
+performance_degraded:
+  lap-detected: { sched: lavd, args: '--powersave --no-core-compaction' }
+";
+    let file = ConfigFile::new(legacy);
+    let migrated = migrate_config(legacy, &file.path).unwrap();
+    assert!(migrated.starts_with("version: 1\n"), "{migrated}");
+    assert!(!migrated.contains("args: '"), "{migrated}");
+    assert!(
+        migrated.contains("- --no-core-compaction"),
+        "performance_degraded not migrated:\n{migrated}"
+    );
+
+    let config = ConfigFile::new(&migrated).load().unwrap();
+    let degraded = config.degraded_mode_set("lap-detected").unwrap();
+    assert_eq!(
+        degraded.select(PowerSource::Ac).unwrap().args,
+        ["--powersave", "--no-core-compaction"]
+    );
+}
+
+#[test]
+fn performance_degraded_picks_by_reason() {
+    let file = ConfigFile::new(&format!(
+        "{VALID}performance_degraded:\n  \
+           default: {{ sched: bpfland, args: [] }}\n  \
+           lap-detected: {{ sched: lavd, args: ['--powersave'] }}\n"
+    ));
+    let config = file.load().unwrap();
+    let sched = |reasons| {
+        config
+            .degraded_mode_set(reasons)
+            .map(|set| set.select(PowerSource::Ac).unwrap().sched.clone())
+    };
+    assert_eq!(sched(""), None);
+    assert_eq!(sched("lap-detected").as_deref(), Some("lavd"));
+    assert_eq!(
+        sched("high-operating-temperature,lap-detected").as_deref(),
+        Some("lavd")
+    );
+    assert_eq!(
+        sched("high-operating-temperature").as_deref(),
+        Some("bpfland")
+    );
+}
+
+#[test]
+fn anchors_and_merge_keys_share_args() {
+    let file = ConfigFile::new(
+        "x-common: &common { sched: lavd, args: ['--autopilot', '--slice-max-us', '5000'] }\n\
+         x-saver: &saver\n  \
+           power-saver: { <<: *common, args: ['--powersave'] }\n\
+         modes:\n  \
+           <<: *saver\n  \
+           performance: *common\n  \
+           balanced:\n    \
+             <<: *common\n    \
+             sched: bpfland\n",
+    );
+    let config = file.load().unwrap();
+    let mode = |p| config.mode_for(p, PowerSource::Ac).unwrap();
+    let performance = mode(Profile::Performance);
+    assert_eq!(performance.sched, "lavd");
+    assert_eq!(performance.args, ["--autopilot", "--slice-max-us", "5000"]);
+    let balanced = mode(Profile::Balanced);
+    assert_eq!(balanced.sched, "bpfland");
+    assert_eq!(balanced.args, performance.args);
+    let power_saver = mode(Profile::PowerSaver);
+    assert_eq!(power_saver.sched, "lavd");
+    assert_eq!(power_saver.args, ["--powersave"]);
+
+    // A mode written out overrides the merged one rather than repeating it.
+    let file = ConfigFile::new(&format!(
+        "x-saver: &saver\n  power-saver: {{ sched: rusty, args: [] }}\n{}",
+        VALID.replace("modes:\n", "modes:\n  <<: *saver\n")
+    ));
+    let config = file.load().unwrap();
+    let power_saver = config
+        .mode_for(Profile::PowerSaver, PowerSource::Ac)
+        .unwrap();
+    assert_eq!(power_saver.sched, "flash");
+}
+
+#[test]
+fn effective_config_loads_back_unchanged() {
+    let file = ConfigFile::new(&format!(
+        "{VALID}presets:\n  \
+           quiet:\n    \
+             default: {{ sched: bpfland, args: [] }}\n\
+         performance_degraded:\n  \
+           default:\n    \
+             ac: {{ sched: lavd, args: [] }}\n    \
+             battery: {{ enabled: false }}\n"
+    ));
+    let effective = file.load().unwrap().effective();
+    assert_eq!(effective["modes"]["balanced"]["args"][0], "--autopilot");
+    assert_eq!(
+        effective["presets"]["quiet"]["performance"]["sched"],
+        "bpfland"
+    );
+    assert_eq!(
+        effective["performance_degraded"]["default"]["battery"]["enabled"],
+        false
+    );
+
+    let again = ConfigFile::new(&serde_yaml::to_string(&effective).unwrap());
+    let mut reloaded = again.load().unwrap().effective();
+    reloaded["path"] = effective["path"].clone();
+    assert_eq!(reloaded, effective);
+}
@@ -0,0 +1,46 @@
+//! `${VAR}` expansion in `args`. Kept in its own test binary, as a single
+//! test, because it sets process environment variables.
+
+use scx_power_sync_dbus::{PowerSource, Profile, load_config};
+use std::env;
+use std::fs;
+
+#[test]
+fn expanded_values_are_not_checked_as_placeholders() {
+    // SAFETY: the only test in this binary, so no other thread reads the
+    // environment meanwhile.
+    unsafe {
+        env::set_var("SCX_POWER_SYNC_TEST_FILTER", "{a,b}");
+        env::set_var("SCX_POWER_SYNC_TEST_TAG", "{fast}");
+    }
+    let path = env::temp_dir().join(format!("scx-power-sync-env-{}.yaml", std::process::id()));
+    let load = |contents: &str| {
+        fs::write(&path, contents).unwrap();
+        load_config(Some(&path)).map_err(|e| format!("{:#}", anyhow::Error::from(e)))
+    };
+
+    for args in [
+        "'--filter ${SCX_POWER_SYNC_TEST_FILTER} --tag ${SCX_POWER_SYNC_TEST_TAG}'",
+        "['--filter', '${SCX_POWER_SYNC_TEST_FILTER}', '--tag', '${SCX_POWER_SYNC_TEST_TAG}']",
+    ] {
+        let config = load(&format!(
+            "modes:\n  default: {{ sched: lavd, args: {args} }}\n"
+        ))
+        .unwrap();
+        let mode = config.mode_for(Profile::Balanced, PowerSource::Ac).unwrap();
+        assert_eq!(
+            mode.args,
+            ["--filter", "{a,b}", "--tag", "{fast}"],
+            "{args}"
+        );
+    }
+
+    // What the user wrote is still checked.
+    let Err(err) =
+        load("modes:\n  default: { sched: lavd, args: '${SCX_POWER_SYNC_TEST_TAG} {fats}' }\n")
+    else {
+        panic!("a misspelled placeholder was accepted");
+    };
+    assert!(err.contains("unknown placeholder '{fats}'"), "{err}");
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,124 @@
+//! `config_search_paths` precedence and the loaders built on it. Kept in its
+//! own test binary, as a single test, because it rewrites the process environment.
+
+use scx_power_sync_dbus::{
+    PowerSource, Profile, config_search_paths, load_config, load_merged_config,
+};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn candidate(dir: &str) -> PathBuf {
+    PathBuf::from(dir)
+        .join("scx-power-sync-dbus")
+        .join("config.yaml")
+}
+
+fn set(key: &str, value: Option<&str>) {
+    // SAFETY: the only test in this binary, so no other thread reads the
+    // environment meanwhile.
+    unsafe {
+        match value {
+            Some(value) => env::set_var(key, value),
+            None => env::remove_var(key),
+        }
+    }
+}
+
+#[test]
+fn search_path_precedence() {
+    set("HOME", Some("/home/u"));
+    set("XDG_CONFIG_HOME", Some("/xdg/home"));
+    set("XDG_CONFIG_DIRS", Some("/xdg/a::/xdg/b"));
+    assert_eq!(
+        config_search_paths(),
+        [
+            candidate("/home/u/.config"),
+            candidate("/xdg/home"),
+            candidate("/xdg/a"),
+            candidate("/xdg/b"),
+            candidate("/etc"),
+        ]
+    );
+
+    // XDG_CONFIG_HOME pointing at ~/.config is listed once; an unset
+    // XDG_CONFIG_DIRS means /etc/xdg.
+    set("XDG_CONFIG_HOME", Some("/home/u/.config"));
+    set("XDG_CONFIG_DIRS", None);
+    assert_eq!(
+        config_search_paths(),
+        [
+            candidate("/home/u/.config"),
+            candidate("/etc/xdg"),
+            candidate("/etc"),
+        ]
+    );
+
+    // Empty XDG_CONFIG_HOME is ignored; no HOME drops the first entry.
+    set("HOME", None);
+    set("XDG_CONFIG_HOME", Some(""));
+    set("XDG_CONFIG_DIRS", Some("/etc"));
+    assert_eq!(config_search_paths(), [candidate("/etc")]);
+
+    // A cleared environment, as under systemd, still leaves the system-wide
+    // locations; an empty HOME doesn't turn into a relative path.
+    set("HOME", Some(""));
+    set("XDG_CONFIG_HOME", None);
+    set("XDG_CONFIG_DIRS", None);
+    assert_eq!(
+        config_search_paths(),
+        [candidate("/etc/xdg"), candidate("/etc")]
+    );
+
+    // An empty XDG_CONFIG_DIRS, or one of only separators, means /etc/xdg too.
+    for empty in ["", "::"] {
+        set("XDG_CONFIG_DIRS", Some(empty));
+        assert_eq!(
+            config_search_paths(),
+            [candidate("/etc/xdg"), candidate("/etc")]
+        );
+    }
+    set("XDG_CONFIG_DIRS", None);
+
+    // load_config takes the first candidate that exists.
+    let root = env::temp_dir().join(format!("scx-power-sync-search-{}", std::process::id()));
+    let home = root.join("home");
+    let xdg = root.join("xdg");
+    for (dir, sched) in [(&home.join(".config"), "lavd"), (&xdg, "rusty")] {
+        let file = dir.join("scx-power-sync-dbus").join("config.yaml");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(
+            &file,
+            format!("modes:\n  default: {{ sched: {sched}, args: [] }}\n"),
+        )
+        .unwrap();
+    }
+    set("HOME", home.to_str());
+    set("XDG_CONFIG_HOME", None);
+    set("XDG_CONFIG_DIRS", xdg.to_str());
+    let loaded = |expected: &PathBuf| {
+        let config = load_config(None).unwrap();
+        assert_eq!(&config.path, expected);
+    };
+    loaded(&candidate(home.join(".config").to_str().unwrap()));
+    fs::remove_dir_all(home.join(".config")).unwrap();
+    loaded(&candidate(xdg.to_str().unwrap()));
+
+    // Merged, a partial file in ~/.config overrides single entries of the
+    // full one below it.
+    let user = candidate(home.join(".config").to_str().unwrap());
+    fs::create_dir_all(user.parent().unwrap()).unwrap();
+    fs::write(
+        &user,
+        "debounce_ms: 7\nmodes:\n  balanced: { sched: lavd, args: [] }\n",
+    )
+    .unwrap();
+    let config = load_merged_config().unwrap();
+    assert_eq!(config.path, user);
+    assert_eq!(config.layers, [candidate(xdg.to_str().unwrap()), user]);
+    assert_eq!(config.debounce.as_millis(), 7);
+    let sched = |p| config.mode_for(p, PowerSource::Ac).unwrap().sched.clone();
+    assert_eq!(sched(Profile::Balanced), "lavd");
+    assert_eq!(sched(Profile::PowerSaver), "rusty");
+    fs::remove_dir_all(&root).unwrap();
+}
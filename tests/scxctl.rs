@@ -0,0 +1,584 @@
+//! `apply_mode` and friends against a scripted scxctl: every invocation is
+//! recorded and answered from a queue of canned replies.
+
+use futures_util::future::BoxFuture;
+use scx_power_sync_dbus::{
+    ApplyOptions, ArgsStyle, Mode, ModeAction, ProcessRunner, ScxRunner, ScxSettings, ScxStatus,
+    ScxSubcommand, ScxctlVersion, apply_mode, detect_scxctl_version, scx_running, scx_status,
+};
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Output};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const NOT_RUNNING: &str = "no scx scheduler running";
+
+#[derive(Debug, Default)]
+struct FakeScx {
+    calls: Mutex<Vec<Vec<String>>>,
+    /// The `env` each call got, in the same order as `calls`.
+    envs: Mutex<Vec<Env>>,
+    replies: Mutex<VecDeque<Output>>,
+}
+
+type Env = Vec<(String, Option<String>)>;
+
+impl FakeScx {
+    fn new(replies: impl IntoIterator<Item = Output>) -> Arc<Self> {
+        Arc::new(FakeScx {
+            calls: Mutex::default(),
+            envs: Mutex::default(),
+            replies: Mutex::new(replies.into_iter().collect()),
+        })
+    }
+
+    fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn envs(&self) -> Vec<Env> {
+        self.envs.lock().unwrap().clone()
+    }
+}
+
+impl ScxRunner for FakeScx {
+    fn run<'a>(
+        &'a self,
+        _bin: &'a Path,
+        args: &'a [OsString],
+        env: &'a [(String, Option<String>)],
+    ) -> BoxFuture<'a, io::Result<Output>> {
+        let call: Vec<String> = args.iter().map(|a| a.to_string_lossy().into()).collect();
+        self.calls.lock().unwrap().push(call.clone());
+        self.envs.lock().unwrap().push(env.to_vec());
+        let reply = self.replies.lock().unwrap().pop_front();
+        Box::pin(async move {
+            reply.ok_or_else(|| io::Error::other(format!("unexpected scxctl call: {call:?}")))
+        })
+    }
+}
+
+fn reply(code: i32, stdout: &str, stderr: &str) -> Output {
+    Output {
+        status: ExitStatus::from_raw(code << 8),
+        stdout: stdout.as_bytes().to_vec(),
+        stderr: stderr.as_bytes().to_vec(),
+    }
+}
+
+fn ok(stdout: &str) -> Output {
+    reply(0, stdout, "")
+}
+
+fn settings(fake: &Arc<FakeScx>, retries: u32) -> ScxSettings {
+    ScxSettings {
+        bin: PathBuf::from("scxctl"),
+        global_args: Vec::new(),
+        retries,
+        retry_delay: Duration::from_millis(1),
+        timeout: Duration::from_secs(5),
+        verify: None,
+        runner: fake.clone(),
+        version: None,
+    }
+}
+
+fn mode(sched: &str, args: &[&str]) -> Mode {
+    Mode {
+        action: ModeAction::Run,
+        sched: sched.into(),
+        args: args.iter().map(|a| a.to_string()).collect(),
+        pre: Vec::new(),
+        post: Vec::new(),
+        epp: None,
+        description: None,
+        force_subcmd: None,
+        args_style: ArgsStyle::Joined,
+        env: Vec::new(),
+    }
+}
+
+fn strs(call: &[&str]) -> Vec<String> {
+    call.iter().map(|a| a.to_string()).collect()
+}
+
+#[tokio::test]
+async fn starts_when_nothing_is_running() {
+    let fake = FakeScx::new([ok(NOT_RUNNING), ok("started")]);
+    apply_mode(
+        &mode("lavd", &["--autopilot"]),
+        &settings(&fake, 0),
+        &ApplyOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        fake.calls(),
+        [
+            strs(&["get"]),
+            strs(&["start", "--sched", "lavd", "--args=--autopilot"])
+        ]
+    );
+}
+
+#[tokio::test]
+async fn switches_when_another_scheduler_is_running() {
+    let fake = FakeScx::new([ok("running bpfland with args: -m all"), ok("switched")]);
+    apply_mode(
+        &mode("lavd", &["--autopilot"]),
+        &settings(&fake, 0),
+        &ApplyOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(fake.calls()[1][0], "switch");
+}
+
+#[tokio::test]
+async fn global_args_precede_every_subcommand() {
+    let fake = FakeScx::new([ok(NOT_RUNNING), ok("started")]);
+    let scx = ScxSettings {
+        global_args: strs(&["--socket", "/run/scx.sock"]),
+        ..settings(&fake, 0)
+    };
+    apply_mode(&mode("lavd", &[]), &scx, &ApplyOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        fake.calls(),
+        [
+            strs(&["--socket", "/run/scx.sock", "get"]),
+            strs(&[
+                "--socket",
+                "/run/scx.sock",
+                "start",
+                "--sched",
+                "lavd",
+                "--args="
+            ])
+        ]
+    );
+}
+
+#[tokio::test]
+async fn args_are_joined_into_one_argument() {
+    let fake = FakeScx::new([ok(NOT_RUNNING), ok("")]);
+    apply_mode(
+        &mode("flash", &["-m", "all", "x y"]),
+        &settings(&fake, 0),
+        &ApplyOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        fake.calls()[1],
+        strs(&["start", "--sched", "flash", "--args=-m all x y"])
+    );
+
+    let fake = FakeScx::new([ok(NOT_RUNNING), ok("")]);
+    apply_mode(
+        &mode("flash", &[]),
+        &settings(&fake, 0),
+        &ApplyOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        fake.calls()[1],
+        strs(&["start", "--sched", "flash", "--args="])
+    );
+}
+
+#[tokio::test]
+async fn list_style_passes_each_token_separately() {
+    let mut m = mode("flash", &["-m", "all", "x y"]);
+    m.args_style = ArgsStyle::List;
+    let fake = FakeScx::new([ok(NOT_RUNNING), ok("")]);
+    apply_mode(&m, &settings(&fake, 0), &ApplyOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        fake.calls()[1],
+        strs(&["start", "--sched", "flash", "--args", "-m", "all", "x y"])
+    );
+
+    m.args.clear();
+    let fake = FakeScx::new([ok(NOT_RUNNING), ok("")]);
+    apply_mode(&m, &settings(&fake, 0), &ApplyOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(fake.calls()[1], strs(&["start", "--sched", "flash"]));
+}
+
+#[tokio::test]
+async fn same_scheduler_and_args_is_skipped_unless_forced() {
+    let running = "running lavd with args: --autopilot";
+    let m = mode("lavd", &["--autopilot"]);
+
+    let fake = FakeScx::new([ok(running)]);
+    apply_mode(&m, &settings(&fake, 0), &ApplyOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(fake.calls(), [strs(&["get"])]);
+
+    let fake = FakeScx::new([ok(running), ok("")]);
+    let opts = ApplyOptions {
+        force: true,
+        ..ApplyOptions::default()
+    };
+    apply_mode(&m, &settings(&fake, 0), &opts).await.unwrap();
+    assert_eq!(fake.calls()[1][0], "switch");
+}
+
+#[tokio::test]
+async fn forced_start_stops_the_running_scheduler_first() {
+    let fake = FakeScx::new([
+        ok("running bpfland with args: -m all"),
+        ok("stopped"),
+        ok(""),
+    ]);
+    let mut m = mode("lavd", &[]);
+    m.force_subcmd = Some(ScxSubcommand::Start);
+    apply_mode(&m, &settings(&fake, 0), &ApplyOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        fake.calls(),
+        [
+            strs(&["get"]),
+            strs(&["stop"]),
+            strs(&["start", "--sched", "lavd", "--args="])
+        ]
+    );
+
+    let fake = FakeScx::new([ok(NOT_RUNNING), ok("")]);
+    apply_mode(&m, &settings(&fake, 0), &ApplyOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(fake.calls()[1][0], "start");
+}
+
+#[tokio::test]
+async fn stop_mode_stops_only_a_running_scheduler() {
+    let mut m = mode("", &[]);
+    m.action = ModeAction::Stop;
+    let fake = FakeScx::new([ok("running bpfland with args: -m all"), ok("stopped")]);
+    apply_mode(&m, &settings(&fake, 0), &ApplyOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(fake.calls(), [strs(&["get"]), strs(&["stop"])]);
+
+    let fake = FakeScx::new([ok(NOT_RUNNING)]);
+    apply_mode(&m, &settings(&fake, 0), &ApplyOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(fake.calls(), [strs(&["get"])]);
+}
+
+#[tokio::test]
+async fn forced_switch_is_used_even_when_nothing_runs() {
+    let fake = FakeScx::new([ok(NOT_RUNNING), ok("")]);
+    let mut m = mode("lavd", &[]);
+    m.force_subcmd = Some(ScxSubcommand::Switch);
+    apply_mode(&m, &settings(&fake, 0), &ApplyOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(fake.calls()[1][0], "switch");
+}
+
+#[tokio::test]
+async fn failed_start_reports_exit_code_and_stderr() {
+    let fake = FakeScx::new([ok(NOT_RUNNING), reply(1, "", "scx_loader not ready\n")]);
+    let err = apply_mode(
+        &mode("lavd", &[]),
+        &settings(&fake, 0),
+        &ApplyOptions::default(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "scxctl start failed (exit=1): scx_loader not ready"
+    );
+}
+
+#[tokio::test]
+async fn failed_start_is_retried() {
+    let fake = FakeScx::new([
+        ok(NOT_RUNNING),
+        reply(1, "", "not ready"),
+        reply(1, "", "not ready"),
+        ok("started"),
+    ]);
+    apply_mode(
+        &mode("lavd", &[]),
+        &settings(&fake, 2),
+        &ApplyOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(fake.calls().len(), 4);
+
+    let fake = FakeScx::new([ok(NOT_RUNNING), reply(1, "", "a"), reply(2, "", "b")]);
+    let err = apply_mode(
+        &mode("lavd", &[]),
+        &settings(&fake, 1),
+        &ApplyOptions::default(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.to_string(), "scxctl start failed (exit=2): b");
+}
+
+#[tokio::test]
+async fn verify_polls_until_the_scheduler_shows_up() {
+    let fake = FakeScx::new([
+        ok(NOT_RUNNING),
+        ok("started"),
+        ok(NOT_RUNNING),
+        ok("running scx_lavd with args: --autopilot"),
+    ]);
+    let scx = ScxSettings {
+        verify: Some(Duration::from_secs(5)),
+        ..settings(&fake, 0)
+    };
+    apply_mode(
+        &mode("lavd", &["--autopilot"]),
+        &scx,
+        &ApplyOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(fake.calls().len(), 4);
+}
+
+#[tokio::test]
+async fn verify_mismatch_is_not_an_apply_failure() {
+    let fake = FakeScx::new(
+        [ok(NOT_RUNNING), ok("started")]
+            .into_iter()
+            .chain(std::iter::repeat_with(|| ok("running bpfland")).take(10)),
+    );
+    let scx = ScxSettings {
+        verify: Some(Duration::from_millis(10)),
+        ..settings(&fake, 0)
+    };
+    apply_mode(&mode("lavd", &[]), &scx, &ApplyOptions::default())
+        .await
+        .unwrap();
+    let calls = fake.calls();
+    assert!(calls.len() > 2);
+    assert!(calls[2..].iter().all(|c| c == &strs(&["get"])), "{calls:?}");
+}
+
+#[tokio::test]
+async fn failing_pre_hook_skips_scxctl() {
+    let fake = FakeScx::new([ok(NOT_RUNNING)]);
+    let mut m = mode("lavd", &[]);
+    m.pre.push("exit 3".into());
+    let err = apply_mode(&m, &settings(&fake, 0), &ApplyOptions::default())
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("pre hook failed"), "{err:#}");
+    assert_eq!(fake.calls(), [strs(&["get"])]);
+}
+
+#[tokio::test]
+async fn mode_env_reaches_hooks_and_start() {
+    let fake = FakeScx::new([ok(NOT_RUNNING), ok("started")]);
+    let mut m = mode("lavd", &[]);
+    m.env = vec![
+        ("HOME".into(), None),
+        ("SCX_POWER_SYNC_TEST".into(), Some("1".into())),
+    ];
+    // Exits non-zero, failing the apply, unless the hook sees both.
+    m.pre
+        .push(r#"test "$SCX_POWER_SYNC_TEST" = 1 && test -z "${HOME+set}""#.into());
+    apply_mode(&m, &settings(&fake, 0), &ApplyOptions::default())
+        .await
+        .unwrap();
+    // The probe isn't part of the mode.
+    assert_eq!(fake.envs(), [Vec::new(), m.env.clone()]);
+}
+
+#[tokio::test]
+async fn dry_run_only_probes() {
+    let fake = FakeScx::new([ok(NOT_RUNNING)]);
+    let opts = ApplyOptions {
+        dry_run: true,
+        ..ApplyOptions::default()
+    };
+    apply_mode(&mode("lavd", &["--autopilot"]), &settings(&fake, 0), &opts)
+        .await
+        .unwrap();
+    assert_eq!(fake.calls(), [strs(&["get"])]);
+}
+
+#[tokio::test]
+async fn status_parses_get_output() {
+    let fake = FakeScx::new([ok(NOT_RUNNING), ok("running lavd with args: --autopilot")]);
+    let scx = settings(&fake, 0);
+    assert!(!scx_running(&scx).await.unwrap());
+    assert!(
+        scx_status(&scx)
+            .await
+            .unwrap()
+            .matches(&mode("lavd", &["--autopilot"]))
+    );
+}
+
+#[test]
+fn name_only_status_agrees_but_does_not_match() {
+    let auto = ScxStatus::parse("running lavd in Auto mode");
+    let lavd = mode("lavd", &["--autopilot"]);
+    assert!(!auto.matches(&lavd));
+    assert!(auto.agrees_with(&lavd));
+    assert!(auto.agrees_with(&mode("scx_lavd", &[])));
+    assert!(!auto.agrees_with(&mode("bpfland", &[])));
+    // Reported args still have to be the mode's.
+    let reported = ScxStatus::parse("running lavd with args: --performance");
+    assert!(!reported.agrees_with(&lavd));
+}
+
+#[test]
+fn get_output_variations_are_recognized() {
+    let running = |sched: &str, args: Option<&[&str]>| ScxStatus::Running {
+        sched: Some(sched.to_owned()),
+        args: args.map(strs),
+    };
+    for stopped in [
+        NOT_RUNNING,
+        "No scx scheduler running\n",
+        "",
+        "  \n",
+        "no scheduler is running",
+        "scx_loader: not running",
+        "scheduler is stopped.",
+        "Stopped",
+    ] {
+        assert_eq!(ScxStatus::parse(stopped), ScxStatus::Stopped, "{stopped:?}");
+    }
+    assert_eq!(
+        ScxStatus::parse("running lavd in Auto mode"),
+        running("lavd", None)
+    );
+    assert_eq!(
+        ScxStatus::parse("running flash with args: -m all\n"),
+        running("flash", Some(&["-m", "all"]))
+    );
+    assert_eq!(
+        ScxStatus::parse(r#"running scx_rusty with arguments "-s 20000""#),
+        running("scx_rusty", Some(&["-s", "20000"]))
+    );
+    assert_eq!(
+        ScxStatus::parse(r#"running bpfland with args ["-m", "all"]"#),
+        running("bpfland", Some(&["-m", "all"]))
+    );
+    // "not running" or "is stopped" about something else is still running.
+    assert_eq!(
+        ScxStatus::parse("running lavd in Auto mode, watchdog not running"),
+        running("lavd", None)
+    );
+    assert_eq!(
+        ScxStatus::parse("running rusty (previous instance is stopped)"),
+        running("rusty", None)
+    );
+    // Unrecognized: assume something is loaded, so the apply switches.
+    let unknown = ScxStatus::Running {
+        sched: None,
+        args: None,
+    };
+    assert_eq!(ScxStatus::parse("état inconnu"), unknown);
+    // Characters whose lowercase form is longer must not shift the match.
+    assert_eq!(
+        ScxStatus::parse("İİİ running lavd with args: --autopilot"),
+        running("lavd", Some(&["--autopilot"]))
+    );
+
+    // A failed `get` only counts as running if it still names a scheduler.
+    assert_eq!(
+        ScxStatus::from_get("Error: connection refused", false),
+        ScxStatus::Stopped
+    );
+    assert_eq!(ScxStatus::from_get("état inconnu", true), unknown);
+    assert_eq!(
+        ScxStatus::from_get("running lavd in Auto mode", false),
+        running("lavd", None)
+    );
+}
+
+// A runner that never returns can only be cut off by dropping it.
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn hung_scxctl_times_out() {
+    #[derive(Debug)]
+    struct Hang;
+    impl ScxRunner for Hang {
+        fn run<'a>(
+            &'a self,
+            _: &'a Path,
+            _: &'a [OsString],
+            _: &'a [(String, Option<String>)],
+        ) -> BoxFuture<'a, io::Result<Output>> {
+            Box::pin(std::future::pending())
+        }
+    }
+    let scx = ScxSettings {
+        timeout: Duration::from_millis(10),
+        runner: Arc::new(Hang),
+        ..settings(&FakeScx::new([]), 0)
+    };
+    let err = scx_status(&scx).await.unwrap_err();
+    assert_eq!(err.to_string(), "scxctl get timed out after 10ms");
+}
+
+#[tokio::test]
+async fn missing_scxctl_is_explained() {
+    let scx = ScxSettings {
+        bin: PathBuf::from("/nonexistent/scxctl"),
+        runner: Arc::new(ProcessRunner),
+        ..settings(&FakeScx::new([]), 0)
+    };
+    let err = apply_mode(&mode("lavd", &[]), &scx, &ApplyOptions::default())
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string()
+            .starts_with("/nonexistent/scxctl has gone missing since startup"),
+        "{err:#}"
+    );
+}
+
+#[test]
+fn scxctl_versions_are_parsed() {
+    let v = |major, minor, patch| ScxctlVersion {
+        major,
+        minor,
+        patch,
+    };
+    assert_eq!(ScxctlVersion::parse("scxctl 1.0.14\n"), Some(v(1, 0, 14)));
+    assert_eq!(ScxctlVersion::parse("scxctl v1.1-rc2"), Some(v(1, 1, 0)));
+    assert_eq!(
+        ScxctlVersion::parse("scxctl 1.0.14+git.abc"),
+        Some(v(1, 0, 14))
+    );
+    assert_eq!(ScxctlVersion::parse("scxctl (devel)"), None);
+    assert_eq!(ScxctlVersion::parse("scxctl 1.2.3.4"), None);
+}
+
+#[tokio::test]
+async fn unknown_scxctl_version_is_not_an_error() {
+    let fake = FakeScx::new([ok("scxctl 1.0.2")]);
+    assert_eq!(
+        detect_scxctl_version(&settings(&fake, 0)).await,
+        ScxctlVersion::parse("1.0.2")
+    );
+    assert_eq!(fake.calls(), [strs(&["--version"])]);
+
+    let fake = FakeScx::new([reply(2, "", "unexpected argument '--version'")]);
+    assert_eq!(detect_scxctl_version(&settings(&fake, 0)).await, None);
+}